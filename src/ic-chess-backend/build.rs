@@ -0,0 +1,22 @@
+// Bakes build metadata into the canister for the `version` query.
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/refs/heads");
+
+    // best effort: a source tarball without git just has no commit
+    if let Ok(out) = Command::new("git").args(["rev-parse", "HEAD"]).output() {
+        if out.status.success() {
+            let commit = String::from_utf8_lossy(&out.stdout);
+            println!("cargo:rustc-env=IC_CHESS_GIT_COMMIT={}", commit.trim());
+        }
+    }
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        println!("cargo:rustc-env=IC_CHESS_BUILD_TIME={}", now.as_secs());
+    }
+}
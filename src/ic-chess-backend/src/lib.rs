@@ -1,18 +1,23 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::{
-    api::{caller, time, management_canister::main::raw_rand}, // raw_rand path is deprecated but OK for now
+    api::{is_controller, msg_caller as caller, time},
+    call::Call,
+    management_canister::raw_rand,
 };
 use ic_cdk_macros::{init, query, update};
+use ic_cdk_timers::TimerId;
 use sha2::{Digest, Sha256};
-use std::{cell::RefCell, collections::BTreeMap, str::FromStr};
+use std::{cell::RefCell, collections::{BTreeMap, BTreeSet}, str::FromStr, time::Duration};
 use base64::Engine; // for .encode()
 
 use shakmaty::{
     Chess, Position, Move as ShMove,
-    san::San,
+    san::{San, SanError},
+    attacks,
     fen::Fen,
-    Color, Role, Square,
-    EnPassantMode,
+    Color, Role, Square, CastlingSide, Rank, Bitboard,
+    CastlingMode, EnPassantMode,
+    zobrist::{Zobrist64, ZobristHash},
 };
 
 // -------------------- Public types (Candid) --------------------
@@ -24,6 +29,24 @@ pub enum GameStatus {
     Stalemate,
     Draw { reason: String },
     Resigned { winner_white: bool },
+    /// The side to move let its per-move deadline pass and the opponent claimed.
+    Timeout { winner_white: bool },
+    /// The loser reached `STRICT_ILLEGAL_LIMIT` illegal moves in a strict game.
+    Forfeit { winner_white: bool },
+    /// Won by a variant rule, e.g. the third check in `Variant::ThreeCheck`.
+    VariantWin { winner_white: bool, reason: String },
+}
+
+/// Rule set of a game. Every variant keeps the standard rules and adds its own
+/// way to win.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Variant {
+    #[default]
+    Standard,
+    /// Giving check for the third time wins.
+    ThreeCheck,
+    /// Moving your king to d4, e4, d5 or e5 wins.
+    KingOfTheHill,
 }
 
 #[derive(candid::CandidType, serde::Deserialize, serde::Serialize, Clone)]
@@ -46,6 +69,248 @@ pub struct GameView {
     pub to_move_white: bool,
     pub white_principal: Option<Principal>,
     pub black_principal: Option<Principal>,
+    /// Number of plies played once the game is over; `None` while ongoing.
+    pub final_ply: Option<u32>,
+    pub white_guest: Option<String>,
+    pub black_guest: Option<String>,
+    /// Squares of the pieces giving check to the side to move (e.g. "b5").
+    pub checkers: Vec<String>,
+    pub auto_queen: bool,
+    /// Deepest matching entry of the built-in opening table, if any.
+    pub eco: Option<String>,
+    pub opening_name: Option<String>,
+    pub confirm_moves: bool,
+    /// Staged move awaiting `confirm_move` (or `guest_confirm_move`), in UCI.
+    pub pending_move: Option<String>,
+    pub strict: bool,
+    /// Illegal move attempts since that side's last legal move (strict games only).
+    pub white_illegal_attempts: u8,
+    pub black_illegal_attempts: u8,
+    /// When each seat was claimed; `None` while it is open.
+    pub white_joined_ns: Option<u64>,
+    pub black_joined_ns: Option<u64>,
+    pub auto_draw: bool,
+    /// Principal in the winning seat; `None` while ongoing, for draws, or when
+    /// a guest won.
+    pub winner: Option<Principal>,
+    pub variant: Variant,
+    pub white_checks_given: u8,
+    pub black_checks_given: u8,
+    pub move_deadline_ns: Option<u64>,
+    /// Time left for the current move; `None` without a deadline or once over.
+    pub move_time_left_ns: Option<u64>,
+    pub fair_resign: bool,
+}
+
+/// Returned by `create_game_v2`: the new game's tokens plus its starting view.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CreateResult {
+    pub id: u64,
+    pub white_token: String,
+    pub black_token: String,
+    pub view: GameView,
+}
+
+/// Named analysis line stored with a game; exported as a PGN variation.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Variation {
+    pub name: String,
+    /// Alternative to the move played at this ply.
+    pub from_ply: u32,
+    pub moves_san: Vec<String>,
+}
+
+/// Per-game settings for `create_game_with_options`. `None` keeps the default.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct GameOptions {
+    /// Promote to a queen when a UCI move omits the piece (default true).
+    pub auto_queen: Option<bool>,
+    /// Moves are staged for `confirm_move`/`guest_confirm_move` instead of played (default false).
+    pub confirm_moves: Option<bool>,
+    /// `STRICT_ILLEGAL_LIMIT` illegal moves in a row forfeit the game (default false).
+    pub strict: Option<bool>,
+    /// End the game at threefold repetition or the fifty-move rule instead of
+    /// allowing `claim_draw` (default true).
+    pub auto_draw: Option<bool>,
+    pub variant: Option<Variant>,
+    /// Time allowed per move; past it the waiting player may `claim_expired`.
+    pub move_deadline_ns: Option<u64>,
+    /// Resigning when the opponent has no mating material draws (default false).
+    pub fair_resign: Option<bool>,
+}
+
+/// Whether each castling move could be played right now (rights, occupancy and
+/// attacked squares all considered). The side not to move is judged as if it
+/// were its turn.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CastlingOptions {
+    pub white_king_side: bool,
+    pub white_queen_side: bool,
+    pub black_king_side: bool,
+    pub black_queen_side: bool,
+}
+
+/// One occupancy mask per piece type. Bit `i` is square `i`, counting a1 = 0,
+/// b1 = 1, ..., h1 = 7, a2 = 8, ..., h8 = 63.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Bitboards {
+    pub white_pawns: u64,
+    pub white_knights: u64,
+    pub white_bishops: u64,
+    pub white_rooks: u64,
+    pub white_queens: u64,
+    pub white_kings: u64,
+    pub black_pawns: u64,
+    pub black_knights: u64,
+    pub black_bishops: u64,
+    pub black_rooks: u64,
+    pub black_queens: u64,
+    pub black_kings: u64,
+}
+
+/// Rough stage of the game, see `game_phase`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Opening,
+    Middlegame,
+    Endgame,
+}
+
+/// Everything stored about a game that matters for settling a dispute, see
+/// `audit`. Move timestamps are not recorded, only creation and last update.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuditRecord {
+    pub id: u64,
+    pub creator: Principal,
+    pub white: Option<Principal>,
+    pub black: Option<Principal>,
+    pub white_guest: Option<String>,
+    pub black_guest: Option<String>,
+    pub random_colors: bool,
+    /// Random-color games only: the player waiting for the color draw.
+    pub first_joiner: Option<Principal>,
+    /// A burned token has been used by a principal and can't seat anyone else.
+    pub white_token_burned: bool,
+    pub black_token_burned: bool,
+    pub start_fen: String,
+    pub moves_uci: Vec<String>,
+    pub status: GameStatus,
+    pub created_ns: u64,
+    pub updated_ns: u64,
+    pub result_callback: Option<Principal>,
+    /// Inputs for checking a `claim_expired` result: the deadline, the last
+    /// move, and when the clock for the move on turn started.
+    pub move_deadline_ns: Option<u64>,
+    pub last_move_ns: u64,
+    pub turn_started_ns: u64,
+}
+
+/// Answer to `sync`. The version is the game's `updated_ns`.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum SyncResult {
+    UpToDate,
+    /// Moves played after the client's known ply, and the new version.
+    Moves(Vec<String>, u64),
+    /// The client's ply doesn't fit this game's history; start over from here.
+    FullResync(Box<GameView>),
+}
+
+/// How a player's finished games ended, see `result_breakdown`.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ResultBreakdown {
+    pub checkmate_wins: u32,
+    pub checkmate_losses: u32,
+    pub resign_wins: u32,
+    pub resign_losses: u32,
+    pub timeout_wins: u32,
+    pub timeout_losses: u32,
+    pub forfeit_wins: u32,
+    pub forfeit_losses: u32,
+    pub variant_wins: u32,
+    pub variant_losses: u32,
+    /// Stalemates and all other draws.
+    pub draws: u32,
+}
+
+/// One side of a `LichessGame`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LichessPlayer {
+    /// "white" or "black".
+    pub color: String,
+    /// Principal text or guest name; `None` while the seat is open.
+    pub name: Option<String>,
+    /// Always `None`: there are no ratings here.
+    pub rating: Option<u32>,
+}
+
+/// `GameView` reshaped after Lichess's game object, for frontends written
+/// against the Lichess API.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LichessGame {
+    pub id: String,
+    pub players: Vec<LichessPlayer>,
+    pub fen: String,
+    #[serde(rename = "lastMove")]
+    pub last_move: Option<String>,
+    /// "started", "mate", "resign", "timeout", "stalemate", "draw", "variantEnd"
+    /// or "unknownFinish" (illegal-move forfeit).
+    pub status: String,
+    /// "white" or "black"; `None` while ongoing or drawn.
+    pub winner: Option<String>,
+}
+
+/// Which build a deployed canister is running, see `version`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VersionInfo {
+    pub crate_version: String,
+    /// `None` if the build had no git checkout.
+    pub git_commit: Option<String>,
+    /// Build time in Unix seconds.
+    pub build_time: Option<String>,
+}
+
+/// Draw-rule counters for the current position, see `rule_state`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RuleState {
+    /// Plies since the last capture or pawn move.
+    pub halfmove_clock: u32,
+    /// How often the current position has occurred, including now.
+    pub current_repetition: u8,
+    /// Highest repetition count of any position in the game.
+    pub max_repetition: u8,
+}
+
+/// Outcome of `try_puzzle_move`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum PuzzleResult {
+    /// Right move; the reply (if any) has been played and the puzzle goes on.
+    Correct,
+    /// Not the solution move; nothing was played.
+    Wrong,
+    /// Right move, and the solution is complete.
+    Solved,
+}
+
+/// Compact bundle for share links with a board preview, see `share_info`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ShareInfo {
+    pub game_id: u64,
+    pub fen: String,
+    pub ply: u32,
+    /// e.g. "In progress", "White wins by checkmate", "Draw by stalemate".
+    pub status_text: String,
+}
+
+/// Same as `GameView` minus the seat principals, safe to hand to spectators.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PublicGameView {
+    pub id: u64,
+    pub fen: String,
+    pub moves_san: Vec<String>,
+    pub status: GameStatus,
+    pub created_ns: u64,
+    pub updated_ns: u64,
+    pub to_move_white: bool,
 }
 
 // -------------------- Internal state --------------------
@@ -53,29 +318,228 @@ pub struct GameView {
 #[derive(Clone)]
 struct GameInternal {
     id: u64,
+    // position the game started from; moves_san replays from here
+    start_fen: String,
     pos: Chess,
     moves_san: Vec<String>,
     white: Option<Principal>,
     black: Option<Principal>,
+    creator: Principal,
     // store only hashes on-chain, never raw tokens
     white_token_hash: [u8; 32],
     black_token_hash: [u8; 32],
     status: GameStatus,
     created_ns: u64,
     updated_ns: u64,
+    // ply index -> comment on the move played at that ply
+    move_comments: BTreeMap<u32, String>,
+    // ply index -> numeric annotation glyph ($1 = !, $2 = ?, ...)
+    move_nags: BTreeMap<u32, u8>,
+    // PGN header overrides; None falls back to the defaults in export_pgn
+    pgn_event: Option<String>,
+    pgn_site: Option<String>,
+    pgn_round: Option<String>,
+    // position hash -> how many times it has occurred (includes the start)
+    repetitions: BTreeMap<u64, u8>,
+    // colors are drawn when the second player joins; until then the first
+    // joiner waits here instead of in a seat
+    random_colors: bool,
+    first_joiner: Option<Principal>,
+    // guest seats have a label instead of a principal; their token is kept
+    // (not burned) and must accompany every guest move
+    white_guest: Option<String>,
+    black_guest: Option<String>,
+    auto_queen: bool,
+    // client_move_id of the last move, if it was applied through make_move with one
+    last_client_move_id: Option<u64>,
+    // canister told about the result via `on_game_result` when the game ends
+    result_callback: Option<Principal>,
+    // last opening-table entry the move list has matched (standard start only)
+    eco: Option<String>,
+    opening_name: Option<String>,
+    // move endpoints only stage the move in pending_move; confirm_move or
+    // guest_confirm_move plays it
+    confirm_moves: bool,
+    pending_move: Option<ShMove>,
+    // strict games: illegal make_move attempts since each side's last legal move
+    strict: bool,
+    white_illegal_attempts: u8,
+    black_illegal_attempts: u8,
+    // when each seat was taken (principal or guest)
+    white_joined_ns: Option<u64>,
+    black_joined_ns: Option<u64>,
+    auto_draw: bool,
+    // seated player who called agree_draw first; cleared by the next move
+    draw_agree_from: Option<Principal>,
+    variant: Variant,
+    // checks delivered by each side, counted in every variant
+    white_checks_given: u8,
+    black_checks_given: u8,
+    // correspondence deadline per move, counted from turn_started_ns
+    move_deadline_ns: Option<u64>,
+    last_move_ns: u64,
+    // puzzles only: the expected line in UCI, solver and replies alternating;
+    // moves_san holds the part played so far
+    puzzle_solution: Option<Vec<String>>,
+    fair_resign: bool,
+    // when the status left Ongoing; unlike updated_ns, later annotations don't move it
+    finished_ns: Option<u64>,
+    // study lines added with add_variation, in insertion order
+    variations: Vec<Variation>,
+}
+
+/// Serialized (candid) form used by export_game_blob/import_game_blob.
+#[derive(CandidType, Deserialize)]
+struct GameBlob {
+    start_fen: String,
+    moves_uci: Vec<String>,
+    white: Option<Principal>,
+    black: Option<Principal>,
+    status: GameStatus,
+    created_ns: u64,
+    updated_ns: u64,
+    // absent in blobs exported before variants existed
+    variant: Option<Variant>,
+    // absent in blobs exported before it was stored; replay needs it to get
+    // past a threefold repetition that was not claimed
+    auto_draw: Option<bool>,
+    // every per-game setting; absent in older blobs
+    options: Option<GameOptions>,
+}
+
+/// Final result of a game, derived from its status.
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    WhiteWins,
+    BlackWins,
+    Draw,
 }
 
 #[derive(Default)]
 struct State {
     next_id: u64,
     games: BTreeMap<u64, GameInternal>,
+    // create_game_with_key key -> game id
+    keys: BTreeMap<String, u64>,
+    // while set, a timer deletes finished games untouched for cleanup_ttl_ns
+    cleanup_timer: Option<TimerId>,
+    cleanup_ttl_ns: u64,
+    // lowest game id the next cleanup tick inspects; wraps to 0
+    cleanup_cursor: u64,
+    // caller -> (failed token joins, start of the counting window); entries
+    // are dropped once their window has passed
+    failed_joins: BTreeMap<Principal, (u32, u64)>,
+    // moves played through any endpoint since install; imported games don't count
+    total_moves: u64,
 }
 
+const MAX_COMMENT_LEN: usize = 500;
+const MAX_PGN_TAG_LEN: usize = 100;
+const MAX_PAGE: u32 = 1000;
+const MAX_BULK_CREATE: u32 = 64;
+const MAX_GUEST_NAME_LEN: usize = 32;
+const MAX_BATCH: usize = 256;
+const MAX_GAME_KEY_LEN: usize = 128;
+/// (ECO code, name, SAN moves from the standard start). A game is labeled with
+/// the entry whose moves it has played exactly, so deeper lines override
+/// shallower ones as the game goes on.
+const OPENINGS: &[(&str, &str, &[&str])] = &[
+    ("B00", "King's Pawn Opening", &["e4"]),
+    ("A40", "Queen's Pawn Opening", &["d4"]),
+    ("A10", "English Opening", &["c4"]),
+    ("A04", "Zukertort Opening", &["Nf3"]),
+    ("A02", "Bird's Opening", &["f4"]),
+    ("A01", "Nimzo-Larsen Attack", &["b3"]),
+    ("C20", "King's Pawn Game", &["e4", "e5"]),
+    ("C40", "King's Knight Opening", &["e4", "e5", "Nf3"]),
+    ("C41", "Philidor Defense", &["e4", "e5", "Nf3", "d6"]),
+    ("C42", "Petrov's Defense", &["e4", "e5", "Nf3", "Nf6"]),
+    ("C44", "King's Knight Opening: Normal Variation", &["e4", "e5", "Nf3", "Nc6"]),
+    ("C45", "Scotch Game", &["e4", "e5", "Nf3", "Nc6", "d4"]),
+    ("C46", "Three Knights Opening", &["e4", "e5", "Nf3", "Nc6", "Nc3"]),
+    ("C47", "Four Knights Game", &["e4", "e5", "Nf3", "Nc6", "Nc3", "Nf6"]),
+    ("C50", "Italian Game", &["e4", "e5", "Nf3", "Nc6", "Bc4"]),
+    ("C50", "Italian Game: Giuoco Piano", &["e4", "e5", "Nf3", "Nc6", "Bc4", "Bc5"]),
+    ("C51", "Italian Game: Evans Gambit", &["e4", "e5", "Nf3", "Nc6", "Bc4", "Bc5", "b4"]),
+    ("C55", "Italian Game: Two Knights Defense", &["e4", "e5", "Nf3", "Nc6", "Bc4", "Nf6"]),
+    ("C60", "Ruy Lopez", &["e4", "e5", "Nf3", "Nc6", "Bb5"]),
+    ("C65", "Ruy Lopez: Berlin Defense", &["e4", "e5", "Nf3", "Nc6", "Bb5", "Nf6"]),
+    ("C68", "Ruy Lopez: Exchange Variation", &["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Bxc6"]),
+    ("C21", "Center Game", &["e4", "e5", "d4"]),
+    ("C23", "Bishop's Opening", &["e4", "e5", "Bc4"]),
+    ("C25", "Vienna Game", &["e4", "e5", "Nc3"]),
+    ("C30", "King's Gambit", &["e4", "e5", "f4"]),
+    ("C33", "King's Gambit Accepted", &["e4", "e5", "f4", "exf4"]),
+    ("B20", "Sicilian Defense", &["e4", "c5"]),
+    ("B21", "Sicilian Defense: Smith-Morra Gambit", &["e4", "c5", "d4", "cxd4", "c3"]),
+    ("B22", "Sicilian Defense: Alapin Variation", &["e4", "c5", "c3"]),
+    ("B23", "Sicilian Defense: Closed", &["e4", "c5", "Nc3"]),
+    ("B30", "Sicilian Defense: Old Sicilian", &["e4", "c5", "Nf3", "Nc6"]),
+    ("B70", "Sicilian Defense: Dragon Variation", &["e4", "c5", "Nf3", "d6", "d4", "cxd4", "Nxd4", "Nf6", "Nc3", "g6"]),
+    ("B90", "Sicilian Defense: Najdorf Variation", &["e4", "c5", "Nf3", "d6", "d4", "cxd4", "Nxd4", "Nf6", "Nc3", "a6"]),
+    ("C00", "French Defense", &["e4", "e6"]),
+    ("C01", "French Defense: Exchange Variation", &["e4", "e6", "d4", "d5", "exd5"]),
+    ("C02", "French Defense: Advance Variation", &["e4", "e6", "d4", "d5", "e5"]),
+    ("C03", "French Defense: Tarrasch Variation", &["e4", "e6", "d4", "d5", "Nd2"]),
+    ("B10", "Caro-Kann Defense", &["e4", "c6"]),
+    ("B12", "Caro-Kann Defense: Advance Variation", &["e4", "c6", "d4", "d5", "e5"]),
+    ("B01", "Scandinavian Defense", &["e4", "d5"]),
+    ("B02", "Alekhine's Defense", &["e4", "Nf6"]),
+    ("B06", "Modern Defense", &["e4", "g6"]),
+    ("B07", "Pirc Defense", &["e4", "d6", "d4", "Nf6", "Nc3", "g6"]),
+    ("D00", "Queen's Pawn Game", &["d4", "d5"]),
+    ("D00", "Queen's Pawn Game: London System", &["d4", "d5", "Bf4"]),
+    ("D06", "Queen's Gambit", &["d4", "d5", "c4"]),
+    ("D10", "Slav Defense", &["d4", "d5", "c4", "c6"]),
+    ("D20", "Queen's Gambit Accepted", &["d4", "d5", "c4", "dxc4"]),
+    ("D30", "Queen's Gambit Declined", &["d4", "d5", "c4", "e6"]),
+    ("A45", "Indian Defense", &["d4", "Nf6"]),
+    ("A56", "Benoni Defense", &["d4", "Nf6", "c4", "c5"]),
+    ("A57", "Benko Gambit", &["d4", "Nf6", "c4", "c5", "d5", "b5"]),
+    ("E00", "Catalan Opening", &["d4", "Nf6", "c4", "e6", "g3"]),
+    ("E12", "Queen's Indian Defense", &["d4", "Nf6", "c4", "e6", "Nf3", "b6"]),
+    ("E20", "Nimzo-Indian Defense", &["d4", "Nf6", "c4", "e6", "Nc3", "Bb4"]),
+    ("E60", "King's Indian Defense", &["d4", "Nf6", "c4", "g6"]),
+    ("D80", "Grünfeld Defense", &["d4", "Nf6", "c4", "g6", "Nc3", "d5"]),
+    ("A80", "Dutch Defense", &["d4", "f5"]),
+    ("A09", "Réti Opening", &["Nf3", "d5", "c4"]),
+];
+/// Longest move list in `OPENINGS`; later plies never change the label.
+const MAX_OPENING_PLIES: usize = 10;
+/// `game_phase`: at most this much non-pawn material (N/B = 3, R = 5, Q = 9,
+/// both sides together; 62 at the start) is an endgame.
+const ENDGAME_MATERIAL: u32 = 26;
+/// `game_phase`: otherwise, positions up to this fullmove number are the opening.
+const OPENING_FULLMOVES: u32 = 10;
+/// Most games one cleanup tick inspects, to stay well inside its instruction limit.
+const MAX_CLEANUP_PER_TICK: usize = 50;
+/// Time between cleanup ticks while auto-cleanup is on.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+/// Token joins refuse a caller after this many failures within
+/// `FAILED_JOIN_WINDOW_NS`, until the window has passed.
+const MAX_FAILED_JOINS: u32 = 10;
+const FAILED_JOIN_WINDOW_NS: u64 = 60 * 1_000_000_000;
+/// Consecutive illegal moves that forfeit a strict game.
+const STRICT_ILLEGAL_LIMIT: u8 = 3;
+/// `simulate_random_game` stops and declares a draw after this many plies.
+const MAX_SIMULATED_PLIES: usize = 500;
+/// Deepest `perft` allowed; depth 4 from the start is ~200k leaves.
+const MAX_PERFT_DEPTH: u8 = 4;
+const MAX_VARIATIONS: usize = 64;
+/// How long a seated player must wait for an opponent before `reclaim_game`.
+const RECLAIM_AFTER_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 thread_local! {
-    static STATE: RefCell<State> = RefCell::new(State {
+    static STATE: RefCell<State> = const { RefCell::new(State {
         next_id: 1,
         games: BTreeMap::new(),
-    });
+        keys: BTreeMap::new(),
+        cleanup_timer: None,
+        cleanup_ttl_ns: 0,
+        cleanup_cursor: 0,
+        failed_joins: BTreeMap::new(),
+        total_moves: 0,
+    }) };
 }
 
 // -------------------- Helpers --------------------
@@ -89,11 +553,146 @@ fn hash_token(s: &str) -> [u8; 32] {
     arr
 }
 
+fn is_seated(g: &GameInternal, who: Principal) -> bool {
+    g.white == Some(who) || g.black == Some(who)
+}
+
+/// Someone, principal or guest, holds the `color` seat.
+fn seat_filled(g: &GameInternal, color: Color) -> bool {
+    match color {
+        Color::White => g.white.is_some() || g.white_guest.is_some(),
+        Color::Black => g.black.is_some() || g.black_guest.is_some(),
+    }
+}
+
+/// Validate a PGN tag value and make it safe to put between quotes.
+/// Empty input means "use the default".
+fn sanitize_pgn_tag(v: &str) -> Result<Option<String>, String> {
+    if v.len() > MAX_PGN_TAG_LEN {
+        return Err(format!("PGN tag longer than {} bytes", MAX_PGN_TAG_LEN));
+    }
+    let clean: String = v.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if c == '"' || c == '\\' { '\'' } else { c })
+        .collect();
+    let clean = clean.trim();
+    Ok(if clean.is_empty() { None } else { Some(clean.to_string()) })
+}
+
+fn new_game(pos: Chess, white_token: &str, black_token: &str) -> GameInternal {
+    new_game_at(pos, white_token, black_token, caller(), time())
+}
+
+/// `new_game` with the creator and clock passed in.
+fn new_game_at(pos: Chess, white_token: &str, black_token: &str, creator: Principal, now: u64) -> GameInternal {
+    let status = compute_status(&pos, 1, true, Variant::Standard);
+    GameInternal {
+        id: 0,
+        start_fen: Fen::from_position(&pos, EnPassantMode::Legal).to_string(),
+        repetitions: BTreeMap::from([(position_key(&pos), 1)]),
+        finished_ns: (!matches!(status, GameStatus::Ongoing)).then_some(now),
+        status,
+        pos,
+        moves_san: vec![],
+        white: None,
+        black: None,
+        creator,
+        white_token_hash: hash_token(white_token),
+        black_token_hash: hash_token(black_token),
+        created_ns: now,
+        updated_ns: now,
+        move_comments: BTreeMap::new(),
+        move_nags: BTreeMap::new(),
+        pgn_event: None,
+        pgn_site: None,
+        pgn_round: None,
+        random_colors: false,
+        first_joiner: None,
+        white_guest: None,
+        black_guest: None,
+        auto_queen: true,
+        last_client_move_id: None,
+        result_callback: None,
+        eco: None,
+        opening_name: None,
+        confirm_moves: false,
+        pending_move: None,
+        strict: false,
+        white_illegal_attempts: 0,
+        black_illegal_attempts: 0,
+        white_joined_ns: None,
+        black_joined_ns: None,
+        auto_draw: true,
+        draw_agree_from: None,
+        variant: Variant::Standard,
+        white_checks_given: 0,
+        black_checks_given: 0,
+        move_deadline_ns: None,
+        last_move_ns: now,
+        puzzle_solution: None,
+        fair_resign: false,
+        variations: vec![],
+    }
+}
+
+fn apply_options(g: &mut GameInternal, opts: &GameOptions) {
+    if let Some(v) = opts.auto_queen {
+        g.auto_queen = v;
+    }
+    if let Some(v) = opts.confirm_moves {
+        g.confirm_moves = v;
+    }
+    if let Some(v) = opts.strict {
+        g.strict = v;
+    }
+    if let Some(v) = opts.auto_draw {
+        g.auto_draw = v;
+    }
+    if let Some(v) = opts.variant {
+        g.variant = v;
+    }
+    if opts.move_deadline_ns.is_some() {
+        g.move_deadline_ns = opts.move_deadline_ns;
+    }
+    if let Some(v) = opts.fair_resign {
+        g.fair_resign = v;
+    }
+}
+
+/// Assign the next id and store the game.
+fn insert_game(mut g: GameInternal) -> u64 {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let id = s.next_id;
+        s.next_id += 1;
+        g.id = id;
+        s.games.insert(id, g);
+        id
+    })
+}
+
 async fn random_token() -> String {
-    let (bytes,) = raw_rand().await.expect("raw_rand failed");
+    let bytes = raw_rand().await.expect("raw_rand failed");
     base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&bytes)
 }
 
+/// Two tokens with distinct hashes, so one token can never match both seats.
+async fn token_pair() -> (String, String) {
+    let first = random_token().await;
+    loop {
+        let second = random_token().await;
+        if hash_token(&second) != hash_token(&first) {
+            return (first, second);
+        }
+    }
+}
+
+/// `token_hash` opens the seat stored as `seat_hash`; a burned (all-zero) seat
+/// never matches.
+fn token_matches(token_hash: [u8; 32], seat_hash: [u8; 32]) -> bool {
+    seat_hash != [0u8; 32] && token_hash == seat_hash
+}
+
 
 fn to_view(g: &GameInternal) -> GameView {
     GameView {
@@ -109,26 +708,286 @@ fn to_view(g: &GameInternal) -> GameView {
         white_principal: g.white,
         black_principal: g.black,
         to_move_white: matches!(g.pos.turn(), Color::White),
+        final_ply: if matches!(g.status, GameStatus::Ongoing) {
+            None
+        } else {
+            Some(g.moves_san.len() as u32)
+        },
+        white_guest: g.white_guest.clone(),
+        black_guest: g.black_guest.clone(),
+        checkers: g.pos.checkers().into_iter().map(|sq| sq.to_string()).collect(),
+        auto_queen: g.auto_queen,
+        eco: g.eco.clone(),
+        opening_name: g.opening_name.clone(),
+        confirm_moves: g.confirm_moves,
+        pending_move: g.pending_move.map(|m| m.to_uci(CastlingMode::Standard).to_string()),
+        strict: g.strict,
+        white_illegal_attempts: g.white_illegal_attempts,
+        black_illegal_attempts: g.black_illegal_attempts,
+        white_joined_ns: g.white_joined_ns,
+        black_joined_ns: g.black_joined_ns,
+        auto_draw: g.auto_draw,
+        winner: match outcome(&g.status) {
+            Some(Outcome::WhiteWins) => g.white,
+            Some(Outcome::BlackWins) => g.black,
+            _ => None,
+        },
+        variant: g.variant,
+        white_checks_given: g.white_checks_given,
+        black_checks_given: g.black_checks_given,
+        move_deadline_ns: g.move_deadline_ns,
+        move_time_left_ns: move_time_left_ns(g, time()),
+        fair_resign: g.fair_resign,
+    }
+}
+
+fn to_public_view(g: &GameInternal) -> PublicGameView {
+    PublicGameView {
+        id: g.id,
+        fen: Fen::from_position(&g.pos, EnPassantMode::Legal).to_string(),
+        moves_san: g.moves_san.clone(),
+        status: g.status.clone(),
+        created_ns: g.created_ns,
+        updated_ns: g.updated_ns,
+        to_move_white: matches!(g.pos.turn(), Color::White),
     }
 }
 
-fn compute_status(pos: &Chess) -> GameStatus {
+/// Parse a FEN into a legal standard-chess position (move counters included).
+fn parse_fen(fen: &str) -> Result<Chess, String> {
+    let fen: Fen = fen.trim().parse().map_err(|_| "Invalid FEN")?;
+    fen.into_position(CastlingMode::Standard).map_err(|_| "Illegal position".into())
+}
+
+/// Replay the first `plies` SAN moves from the starting position.
+fn replay(g: &GameInternal, plies: usize) -> Result<Chess, String> {
+    replay_with(g, plies, |_, _| {})
+}
+
+/// Like `replay`, calling `visit(position_before, move)` for every ply.
+fn replay_with(g: &GameInternal, plies: usize, mut visit: impl FnMut(&Chess, ShMove)) -> Result<Chess, String> {
+    let mut pos = parse_fen(&g.start_fen)?;
+    for (i, san) in g.moves_san.iter().take(plies).enumerate() {
+        let m = san.parse::<San>().ok()
+            .and_then(|s| s.to_move(&pos).ok())
+            .ok_or_else(|| format!("Ply {} ({}) does not replay", i, san))?;
+        visit(&pos, m);
+        pos = pos.play(m).map_err(|_| format!("Ply {} ({}) does not replay", i, san))?;
+    }
+    Ok(pos)
+}
+
+/// Numbered SAN moves with NAGs and comments, each token followed by a space.
+fn pgn_movetext(g: &GameInternal) -> Result<String, String> {
+    let mut out = String::new();
+    let start = parse_fen(&g.start_fen)?;
+    let mut move_no = start.fullmoves().get();
+    let mut white = start.turn() == Color::White;
+    for (ply, san) in g.moves_san.iter().enumerate() {
+        let interrupted = ply > 0
+            && (g.move_comments.contains_key(&(ply as u32 - 1))
+                || g.variations.iter().any(|v| v.from_ply == ply as u32 - 1));
+        if white {
+            out.push_str(&format!("{}. {} ", move_no, san));
+        } else if ply == 0 || interrupted {
+            // black's move at the start or after a comment/variation needs its own move number
+            out.push_str(&format!("{}... {} ", move_no, san));
+        } else {
+            out.push_str(&format!("{} ", san));
+        }
+        if let Some(n) = g.move_nags.get(&(ply as u32)) {
+            out.push_str(&format!("${} ", n));
+        }
+        if let Some(c) = g.move_comments.get(&(ply as u32)) {
+            out.push_str(&format!("{{{}}} ", c));
+        }
+        for v in g.variations.iter().filter(|v| v.from_ply == ply as u32) {
+            let line = variation_movetext(&v.moves_san, move_no, white);
+            if v.name.is_empty() {
+                out.push_str(&format!("({}) ", line));
+            } else {
+                out.push_str(&format!("({{{}}} {}) ", v.name, line));
+            }
+        }
+        if !white {
+            move_no += 1;
+        }
+        white = !white;
+    }
+    Ok(out)
+}
+
+/// Numbered SAN for a variation line starting at `move_no` with `white` to move.
+fn variation_movetext(moves_san: &[String], mut move_no: u32, mut white: bool) -> String {
+    let mut parts = Vec::with_capacity(moves_san.len());
+    for (i, san) in moves_san.iter().enumerate() {
+        if white {
+            parts.push(format!("{}. {}", move_no, san));
+        } else if i == 0 {
+            parts.push(format!("{}... {}", move_no, san));
+        } else {
+            parts.push(san.clone());
+        }
+        if !white {
+            move_no += 1;
+        }
+        white = !white;
+    }
+    parts.join(" ")
+}
+
+/// White minus black material in centipawns (P 100, N/B 300, R 500, Q 900).
+fn material_balance(pos: &Chess) -> i32 {
+    let board = pos.board();
+    [(Role::Pawn, 100), (Role::Knight, 300), (Role::Bishop, 300), (Role::Rook, 500), (Role::Queen, 900)]
+        .into_iter()
+        .map(|(role, value)| {
+            let white = (board.by_role(role) & board.white()).count() as i32;
+            let black = (board.by_role(role) & board.black()).count() as i32;
+            (white - black) * value
+        })
+        .sum()
+}
+
+/// Leaf nodes of the legal move tree `depth` plies deep.
+fn perft_count(pos: &Chess, depth: u8) -> u64 {
+    let moves = pos.legal_moves();
+    if depth <= 1 {
+        return if depth == 0 { 1 } else { moves.len() as u64 };
+    }
+    moves.iter()
+        .map(|m| {
+            let mut next = pos.clone();
+            next.play_unchecked(*m);
+            perft_count(&next, depth - 1)
+        })
+        .sum()
+}
+
+/// `None` while the game is still ongoing.
+fn outcome(status: &GameStatus) -> Option<Outcome> {
+    match status {
+        GameStatus::Ongoing => None,
+        GameStatus::Checkmate { winner_white }
+        | GameStatus::Resigned { winner_white }
+        | GameStatus::Timeout { winner_white }
+        | GameStatus::Forfeit { winner_white }
+        | GameStatus::VariantWin { winner_white, .. } => {
+            Some(if *winner_white { Outcome::WhiteWins } else { Outcome::BlackWins })
+        }
+        GameStatus::Stalemate | GameStatus::Draw { .. } => Some(Outcome::Draw),
+    }
+}
+
+/// The current position has occurred at least three times.
+fn threefold_holds(g: &GameInternal) -> bool {
+    g.repetitions.get(&position_key(&g.pos)).is_some_and(|n| *n >= 3)
+}
+
+/// Fifty moves by each side without a capture or pawn move.
+fn fifty_move_holds(pos: &Chess) -> bool {
+    pos.halfmoves() >= 100
+}
+
+/// One-line human-readable result, e.g. "Black wins by resignation".
+fn status_text(status: &GameStatus) -> String {
+    let side = |winner_white: bool| if winner_white { "White" } else { "Black" };
+    match status {
+        GameStatus::Ongoing => "In progress".into(),
+        GameStatus::Checkmate { winner_white } => format!("{} wins by checkmate", side(*winner_white)),
+        GameStatus::Resigned { winner_white } => format!("{} wins by resignation", side(*winner_white)),
+        GameStatus::Timeout { winner_white } => format!("{} wins on time", side(*winner_white)),
+        GameStatus::Forfeit { winner_white } => format!("{} wins by forfeit", side(*winner_white)),
+        GameStatus::VariantWin { winner_white, reason } => format!("{} wins by {}", side(*winner_white), reason),
+        GameStatus::Stalemate => "Draw by stalemate".into(),
+        GameStatus::Draw { reason } => format!("Draw by {}", reason),
+    }
+}
+
+/// Hash used for repetition counting (ignores the move counters).
+fn position_key(pos: &Chess) -> u64 {
+    pos.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0
+}
+
+/// Neither side can possibly mate: bare kings, a lone minor piece, or only
+/// bishops all on one square color (K+B vs K+B with opposite-colored bishops
+/// can still mate, so it is not included). In three-check any piece can still
+/// give check, so only bare kings count; in king of the hill a king can always
+/// still walk to the center.
+fn insufficient_material(pos: &Chess, variant: Variant) -> bool {
+    match variant {
+        Variant::Standard => pos.is_insufficient_material(),
+        Variant::ThreeCheck => pos.board().occupied() == pos.board().kings(),
+        Variant::KingOfTheHill => false,
+    }
+}
+
+/// `color` has no way left to win under `variant`'s rules.
+fn cannot_win(pos: &Chess, color: Color, variant: Variant) -> bool {
+    match variant {
+        Variant::Standard => pos.has_insufficient_material(color),
+        Variant::ThreeCheck => (pos.board().by_color(color) & !pos.board().kings()).is_empty(),
+        Variant::KingOfTheHill => false,
+    }
+}
+
+/// `repetitions` is how often `pos` has occurred so far, including now.
+/// Without `auto_draw`, threefold repetition and the fifty-move rule only allow
+/// `claim_draw`; the game ends automatically at fivefold or 75 moves.
+fn compute_status(pos: &Chess, repetitions: u8, auto_draw: bool, variant: Variant) -> GameStatus {
     // Any legal moves?
-    let mut has_any = false;
-    for _ in pos.legal_moves() { has_any = true; break; }
-    if !has_any {
+    if pos.legal_moves().is_empty() {
         // No legal moves ⇒ checkmate or stalemate
         let in_check = !pos.checkers().is_empty();
         if in_check {
+            // the mated side is to move, so the winner is whoever just moved
             let winner_white = !matches!(pos.turn(), Color::White);
             return GameStatus::Checkmate { winner_white };
         } else {
             return GameStatus::Stalemate;
         }
     }
+    if insufficient_material(pos, variant) {
+        return GameStatus::Draw { reason: "insufficient material".into() };
+    }
+    if auto_draw && repetitions >= 3 {
+        return GameStatus::Draw { reason: "threefold repetition".into() };
+    }
+    if auto_draw && fifty_move_holds(pos) {
+        return GameStatus::Draw { reason: "fifty-move rule".into() };
+    }
+    if repetitions >= 5 {
+        return GameStatus::Draw { reason: "fivefold repetition".into() };
+    }
+    if pos.halfmoves() >= 150 {
+        return GameStatus::Draw { reason: "seventy-five-move rule".into() };
+    }
     GameStatus::Ongoing
 }
 
+/// Relabel the opening if the moves so far are exactly a table entry.
+fn classify_opening(g: &mut GameInternal) {
+    if g.moves_san.len() > MAX_OPENING_PLIES
+        || g.start_fen != Fen::from_position(&Chess::default(), EnPassantMode::Legal).to_string()
+    {
+        return;
+    }
+    if let Some((eco, name, _)) = OPENINGS.iter().find(|(_, _, line)| *line == g.moves_san.as_slice()) {
+        g.eco = Some(eco.to_string());
+        g.opening_name = Some(name.to_string());
+    }
+}
+
+/// Best-effort one-way `on_game_result(game_id, status)` to the registered
+/// callback canister; delivery failures are ignored.
+fn notify_result(g: &GameInternal) {
+    if let Some(target) = g.result_callback {
+        let _ = Call::unbounded_wait(target, "on_game_result")
+            .with_args(&(g.id, g.status.clone()))
+            .oneway();
+    }
+}
+
 // Parse a simple UCI string like "e2e4" or "e7e8q"
 fn parse_uci_to_move(pos: &Chess, mv: &str) -> Option<ShMove> {
     if mv.len() < 4 { return None; }
@@ -145,7 +1004,9 @@ fn parse_uci_to_move(pos: &Chess, mv: &str) -> Option<ShMove> {
     } else { None };
 
     for m in pos.legal_moves() {
-        if m.from() == Some(from) && m.to() == to {
+        // castling is accepted as king to its destination (e1g1) or king takes rook (e1h1)
+        let king_to = m.castling_side().map(|side| side.king_to(pos.turn()));
+        if m.from() == Some(from) && (m.to() == to || king_to == Some(to)) {
             if let Some(pr) = promo_role {
                 if m.promotion() == Some(pr) { return Some(m); }
             } else {
@@ -158,49 +1019,1026 @@ fn parse_uci_to_move(pos: &Chess, mv: &str) -> Option<ShMove> {
     None
 }
 
-/// Try UCI first, then SAN
-fn parse_move_with_autopromo(pos: &Chess, mv: &str) -> Result<ShMove, String> {
-    if let Some(m) = parse_uci_to_move(pos, mv) {
+/// Moves of the side to move's piece on `from` that follow the piece's movement
+/// rules but may leave its own king in check. Castling is not included.
+fn pseudo_legal_moves_from(pos: &Chess, from: Square) -> Vec<ShMove> {
+    let board = pos.board();
+    let us = pos.turn();
+    let Some(piece) = board.piece_at(from).filter(|p| p.color == us) else {
+        return vec![];
+    };
+    let mut out = vec![];
+    let targets = if piece.role == Role::Pawn {
+        let mut t = attacks::pawn_attacks(us, from) & board.by_color(!us);
+        let step = us.fold_wb(8, -8);
+        if let Some(one) = from.offset(step).filter(|sq| !board.occupied().contains(*sq)) {
+            t.add(one);
+            if from.rank() == us.relative_rank(Rank::Second) {
+                if let Some(two) = one.offset(step).filter(|sq| !board.occupied().contains(*sq)) {
+                    t.add(two);
+                }
+            }
+        }
+        if let Some(ep) = pos.maybe_ep_square().filter(|sq| attacks::pawn_attacks(us, from).contains(*sq)) {
+            out.push(ShMove::EnPassant { from, to: ep });
+        }
+        t
+    } else {
+        attacks::attacks(from, piece, board.occupied()) & !board.by_color(us)
+    };
+    for to in targets {
+        let capture = board.role_at(to);
+        if piece.role == Role::Pawn && to.rank() == us.relative_rank(Rank::Eighth) {
+            for promotion in [Role::Queen, Role::Rook, Role::Bishop, Role::Knight] {
+                out.push(ShMove::Normal { role: Role::Pawn, from, capture, to, promotion: Some(promotion) });
+            }
+        } else {
+            out.push(ShMove::Normal { role: piece.role, from, capture, to, promotion: None });
+        }
+    }
+    out
+}
+
+/// Long algebraic like "Ng1-f3", "e4xd5" or "e7-e8=Q" as (piece, UCI), so
+/// "Ng1-f3" becomes (Some(Knight), "g1f3"). `None` for input without '-' or 'x'.
+fn long_algebraic_to_uci(mv: &str) -> Option<(Option<Role>, String)> {
+    if !mv.contains(['-', 'x']) {
+        return None;
+    }
+    let body = mv.trim_end_matches(['+', '#']);
+    let role = body.chars().next().filter(char::is_ascii_uppercase)
+        .and_then(|c| Role::from_char(c.to_ascii_lowercase()));
+    let body = if role.is_some() { &body[1..] } else { body };
+    Some((role, body.chars().filter(|c| !matches!(c, '-' | 'x' | '=')).collect()))
+}
+
+/// The side to move's piece on `from` shields its king from a slider, and
+/// moving it to `to` would step off that line.
+fn breaks_pin(pos: &Chess, from: Square, to: Square) -> bool {
+    let board = pos.board();
+    let us = pos.turn();
+    let Some(king) = board.king_of(us) else { return false };
+    if from == king || attacks::aligned(king, from, to) {
+        return false;
+    }
+    let occupied = board.occupied();
+    (board.attacks_to(king, !us, occupied.without(from)) & !board.attacks_to(king, !us, occupied)).any()
+}
+
+/// Error for a move that matched nothing legal. `pseudo_legal` is a move the
+/// piece could make by its movement rules, i.e. one only its king's safety rules out.
+fn illegal_move_error(pos: &Chess, pseudo_legal: Option<ShMove>) -> String {
+    let Some((from, to)) = pseudo_legal.and_then(|m| Some((m.from()?, m.to()))) else {
+        return "Illegal move".into();
+    };
+    if !breaks_pin(pos, from, to) {
+        "Illegal move: it would leave your king in check".into()
+    } else if pos.legal_moves().iter().any(|m| m.from() == Some(from)) {
+        "That piece is pinned and can only move along the pin".into()
+    } else {
+        "That piece is pinned and cannot move".into()
+    }
+}
+
+/// Try UCI first, then SAN. Without `auto_queen`, a UCI promotion must name its piece.
+fn parse_move_with_autopromo(pos: &Chess, mv: &str, auto_queen: bool) -> Result<ShMove, String> {
+    // engines write a null move as "0000"
+    if mv == "0000" || mv == "--" {
+        return Err("Null moves are not allowed".into());
+    }
+    let (role, long) = long_algebraic_to_uci(mv).unzip();
+    let uci = long.as_deref().unwrap_or(mv);
+    if let Some(m) = parse_uci_to_move(pos, uci) {
+        if role.flatten().is_some_and(|r| r != m.role()) {
+            return Err(illegal_move_error(pos, None));
+        }
+        if !auto_queen && m.is_promotion() && uci.len() == 4 {
+            return Err("Promotion piece required (e.g. 'e7e8n')".into());
+        }
         return Ok(m);
     }
+    let square = |r| uci.get(r).and_then(|s: &str| Square::from_str(s).ok());
+    if let (4 | 5, Some(from), Some(to)) = (uci.len(), square(0..2), square(2..4)) {
+        let promo = uci[4..].chars().next().and_then(|c| Role::from_char(c.to_ascii_lowercase()));
+        let pseudo_legal = pseudo_legal_moves_from(pos, from).into_iter()
+            .find(|m| m.to() == to && (promo.is_none() || m.promotion() == promo));
+        return Err(illegal_move_error(pos, pseudo_legal));
+    }
     if let Ok(san) = mv.parse::<San>() {
-        return san.to_move(pos).map_err(|_| "Illegal move".into());
+        return match san.to_move(pos) {
+            Ok(m) => Ok(m),
+            Err(SanError::AmbiguousSan) => Err("Ambiguous move; name the origin file or rank".into()),
+            Err(_) => {
+                let pseudo_legal = pos.us().into_iter()
+                    .flat_map(|from| pseudo_legal_moves_from(pos, from))
+                    .find(|m| san.matches(*m));
+                Err(illegal_move_error(pos, pseudo_legal))
+            }
+        };
     }
     Err("Move must be SAN (e.g. 'e4') or UCI ('e2e4'/'e7e8q')".into())
 }
 
-// -------------------- Lifecycle --------------------
+/// Run a token join for `who` under the failed-attempt limit: refused while the
+/// caller has `MAX_FAILED_JOINS` failures in the current window, and any error
+/// from `join` counts as one more. Expired windows are pruned on the way.
+fn limit_failed_joins<T>(
+    st: &mut State,
+    who: Principal,
+    join: impl FnOnce(&mut State) -> Result<T, String>,
+) -> Result<T, String> {
+    let now = time();
+    st.failed_joins.retain(|_, (_, since)| now.saturating_sub(*since) < FAILED_JOIN_WINDOW_NS);
+    if st.failed_joins.get(&who).is_some_and(|(n, _)| *n >= MAX_FAILED_JOINS) {
+        return Err("Too many failed attempts, try later".into());
+    }
+    let joined = join(st);
+    if joined.is_ok() {
+        st.failed_joins.remove(&who);
+    } else {
+        st.failed_joins.entry(who).or_insert((0, now)).0 += 1;
+    }
+    joined
+}
 
-#[init]
-fn init() {}
+/// Seat `who` using a one-time token (burned on success). Returns the claimed color.
+fn claim_seat(g: &mut GameInternal, who: Principal, token: &str) -> Result<Color, String> {
+    if g.random_colors {
+        return Err("Colors are drawn at pairing in this game; use join_by_token".into());
+    }
+    // Already seated?
+    if is_seated(g, who) {
+        return Err("You already occupy a seat in this game".into());
+    }
 
-// -------------------- Queries --------------------
+    let th = hash_token(token);
 
-#[query]
-fn get_game(id: u64) -> Option<GameView> {
-    STATE.with(|s| s.borrow().games.get(&id).map(to_view))
+    if token_matches(th, g.white_token_hash) {
+        if g.white.is_some() || g.white_guest.is_some() {
+            return Err("White seat already taken".into());
+        }
+        g.white = Some(who);
+        g.white_token_hash = [0u8; 32]; // burn
+        g.updated_ns = time();
+        g.white_joined_ns = Some(g.updated_ns);
+        return Ok(Color::White);
+    } else if token_matches(th, g.black_token_hash) {
+        if g.black.is_some() || g.black_guest.is_some() {
+            return Err("Black seat already taken".into());
+        }
+        g.black = Some(who);
+        g.black_token_hash = [0u8; 32]; // burn
+        g.updated_ns = time();
+        g.black_joined_ns = Some(g.updated_ns);
+        return Ok(Color::Black);
+    }
+
+    Err("Invalid or already-used token".into())
 }
 
-#[query]
-fn list_recent(offset_desc: u64, limit: u32) -> Vec<GameView> {
-    STATE.with(|s| {
-        let games = &s.borrow().games;
-        let mut ids: Vec<_> = games.keys().cloned().collect();
-        ids.sort_unstable_by(|a,b| b.cmp(a)); // newest first
-        ids.into_iter()
-            .skip(offset_desc as usize)
-            .take(limit as usize)
-            .filter_map(|id| games.get(&id).map(to_view))
-            .collect()
-    })
+/// `who` may redeem `token` in a random-color game: not yet waiting or seated,
+/// and the token opens an unused seat.
+fn check_random_seat(g: &GameInternal, who: Principal, token: &str) -> Result<(), String> {
+    if is_seated(g, who) || g.first_joiner == Some(who) {
+        return Err("You already occupy a seat in this game".into());
+    }
+    let th = hash_token(token);
+    if !token_matches(th, g.white_token_hash) && !token_matches(th, g.black_token_hash) {
+        return Err("Invalid or already-used token".into());
+    }
+    Ok(())
 }
 
-#[ic_cdk::query]
-fn my_role(game_id: u64) -> PlayerRole {
-    STATE.with(|s| {
-        let who = caller();
-        if let Some(g) = s.borrow().games.get(&game_id) {
-            if g.white == Some(who) {
+/// Token join for random-color games. The first joiner waits in `first_joiner`;
+/// the second triggers the draw, `white_first` deciding whether the first joiner
+/// gets white.
+fn claim_random_seat(g: &mut GameInternal, who: Principal, token: &str, white_first: Option<bool>) -> Result<(), String> {
+    check_random_seat(g, who, token)?;
+    let th = hash_token(token);
+
+    match g.first_joiner {
+        None => g.first_joiner = Some(who),
+        Some(first) => {
+            let white_first = white_first.ok_or("Missing color draw")?;
+            let (w, b) = if white_first { (first, who) } else { (who, first) };
+            g.white = Some(w);
+            g.black = Some(b);
+            g.first_joiner = None;
+            // the seats only exist from the draw on
+            g.white_joined_ns = Some(time());
+            g.black_joined_ns = g.white_joined_ns;
+        }
+    }
+    if token_matches(th, g.white_token_hash) {
+        g.white_token_hash = [0u8; 32]; // burn
+    } else {
+        g.black_token_hash = [0u8; 32]; // burn
+    }
+    g.updated_ns = time();
+    Ok(())
+}
+
+/// When the side to move started thinking: the last move, or for the first
+/// move the time the second seat was filled.
+fn turn_started_ns(g: &GameInternal) -> u64 {
+    if g.moves_san.is_empty() {
+        if let (Some(w), Some(b)) = (g.white_joined_ns, g.black_joined_ns) {
+            return w.max(b);
+        }
+    }
+    g.last_move_ns
+}
+
+/// Time left at `now` for the move on turn; `None` without a deadline or once
+/// the game is over.
+fn move_time_left_ns(g: &GameInternal, now: u64) -> Option<u64> {
+    g.move_deadline_ns
+        .filter(|_| matches!(g.status, GameStatus::Ongoing))
+        .map(|d| d.saturating_sub(now.saturating_sub(turn_started_ns(g))))
+}
+
+/// The move on turn has run past `deadline` at `now`.
+fn deadline_passed(g: &GameInternal, deadline: u64, now: u64) -> bool {
+    now.saturating_sub(turn_started_ns(g)) > deadline
+}
+
+fn ensure_playable(g: &GameInternal) -> Result<(), String> {
+    if !matches!(g.status, GameStatus::Ongoing) {
+        return Err("Game finished".into());
+    }
+    if g.random_colors && (g.white.is_none() || g.black.is_none()) {
+        return Err("Colors have not been drawn yet".into());
+    }
+    Ok(())
+}
+
+/// In strict games, count a rejected move against the side to move and forfeit
+/// the game for it at `STRICT_ILLEGAL_LIMIT`. Returns `err`, extended on forfeit.
+fn note_illegal_attempt(g: &mut GameInternal, err: String) -> String {
+    if !g.strict {
+        return err;
+    }
+    let attempts = match g.pos.turn() {
+        Color::White => &mut g.white_illegal_attempts,
+        Color::Black => &mut g.black_illegal_attempts,
+    };
+    *attempts += 1;
+    if *attempts < STRICT_ILLEGAL_LIMIT {
+        return err;
+    }
+    g.status = GameStatus::Forfeit { winner_white: g.pos.turn() == Color::Black };
+    g.pending_move = None;
+    g.updated_ns = time();
+    g.finished_ns = Some(g.updated_ns);
+    notify_result(g);
+    format!("{}; game forfeited after {} illegal moves", err, STRICT_ILLEGAL_LIMIT)
+}
+
+/// `who` may move for the side to move (or that seat is still unclaimed).
+fn authorize_move(g: &GameInternal, who: Principal) -> Result<(), String> {
+    ensure_playable(g)?;
+    if g.puzzle_solution.is_some() {
+        return Err("This is a puzzle; use try_puzzle_move".into());
+    }
+
+    // Enforce turn by seat (if a seat has been claimed)
+    match g.pos.turn() {
+        Color::White => {
+            if g.white_guest.is_some() { return Err("White is a guest seat; use guest_move".into()); }
+            if g.white.is_some() && g.white != Some(who) { return Err("Not white".into()); }
+        }
+        Color::Black => {
+            if g.black_guest.is_some() { return Err("Black is a guest seat; use guest_move".into()); }
+            if g.black.is_some() && g.black != Some(who) { return Err("Not black".into()); }
+        }
+    }
+    Ok(())
+}
+
+/// `token` belongs to the guest seat that is to move.
+fn authorize_guest_move(g: &GameInternal, token: &str) -> Result<(), String> {
+    ensure_playable(g)?;
+    let (guest, token_hash) = match g.pos.turn() {
+        Color::White => (&g.white_guest, g.white_token_hash),
+        Color::Black => (&g.black_guest, g.black_token_hash),
+    };
+    if guest.is_none() {
+        return Err("Side to move is not a guest seat".into());
+    }
+    if !token_matches(hash_token(token), token_hash) {
+        return Err("Wrong token for the side to move".into());
+    }
+    Ok(())
+}
+
+/// In `confirm_moves` games stage `mv` as the pending move (replacing any
+/// earlier one); otherwise play it. Returns whether the move was played.
+fn submit_move(g: &mut GameInternal, mv: &str) -> Result<bool, String> {
+    if g.confirm_moves {
+        g.pending_move = Some(parse_move_with_autopromo(&g.pos, mv, g.auto_queen)?);
+        g.updated_ns = time();
+        return Ok(false);
+    }
+    play_move(g, mv)?;
+    Ok(true)
+}
+
+/// Play `mv` for the side to move; callers have already authorized it.
+fn play_move(g: &mut GameInternal, mv: &str) -> Result<(), String> {
+    let m = parse_move_with_autopromo(&g.pos, mv, g.auto_queen)?;
+    play_parsed_move(g, m, time())
+}
+
+/// Play an already parsed move, stamping it with `now`.
+fn play_parsed_move(g: &mut GameInternal, m: ShMove, now: u64) -> Result<(), String> {
+    let mover = g.pos.turn();
+    let san_str = San::from_move(&g.pos, m).to_string();
+
+    let new_pos = g.pos.clone().play(m).map_err(|_| "Illegal move")?;
+    g.pos = new_pos;
+    g.moves_san.push(san_str);
+    g.last_client_move_id = None;
+    g.pending_move = None;
+    g.draw_agree_from = None;
+    g.last_move_ns = now;
+    match mover {
+        Color::White => g.white_illegal_attempts = 0,
+        Color::Black => g.black_illegal_attempts = 0,
+    }
+    classify_opening(g);
+
+    let seen = g.repetitions.entry(position_key(&g.pos)).or_insert(0);
+    *seen = seen.saturating_add(1);
+    g.status = compute_status(&g.pos, *seen, g.auto_draw, g.variant);
+    if g.pos.is_check() {
+        let given = match mover {
+            Color::White => &mut g.white_checks_given,
+            Color::Black => &mut g.black_checks_given,
+        };
+        *given = given.saturating_add(1);
+        if g.variant == Variant::ThreeCheck && *given >= 3 && matches!(g.status, GameStatus::Ongoing) {
+            g.status = GameStatus::VariantWin { winner_white: mover == Color::White, reason: "three checks".into() };
+        }
+    }
+    if g.variant == Variant::KingOfTheHill
+        && matches!(g.status, GameStatus::Ongoing)
+        && g.pos.board().king_of(mover).is_some_and(|k| Bitboard::CENTER.contains(k))
+    {
+        g.status = GameStatus::VariantWin { winner_white: mover == Color::White, reason: "king of the hill".into() };
+    }
+    g.updated_ns = now;
+    if !matches!(g.status, GameStatus::Ongoing) {
+        g.finished_ns = Some(now);
+        notify_result(g);
+    }
+    Ok(())
+}
+
+// -------------------- Lifecycle --------------------
+
+#[init]
+fn init() {}
+
+/// Auto-cleanup timer body: inspect the next `MAX_CLEANUP_PER_TICK` games after
+/// the cursor and delete the finished ones whose last update is older than the TTL.
+fn cleanup_tick() {
+    let now = time();
+    STATE.with(|s| {
+        let mut st = s.borrow_mut();
+        let ttl = st.cleanup_ttl_ns;
+        let batch: Vec<&GameInternal> = st.games.range(st.cleanup_cursor..).map(|(_, g)| g).take(MAX_CLEANUP_PER_TICK).collect();
+        let expired: Vec<u64> = batch.iter()
+            .filter(|g| !matches!(g.status, GameStatus::Ongoing) && now.saturating_sub(g.updated_ns) >= ttl)
+            .map(|g| g.id)
+            .collect();
+        // a short batch reached the end of the map; start over next tick
+        st.cleanup_cursor = match batch.last() {
+            Some(g) if batch.len() == MAX_CLEANUP_PER_TICK => g.id + 1,
+            _ => 0,
+        };
+        if expired.is_empty() {
+            return;
+        }
+        for id in &expired {
+            st.games.remove(id);
+        }
+        st.keys.retain(|_, id| !expired.contains(id));
+    })
+}
+
+// -------------------- Queries --------------------
+
+/// Crate version plus the commit and time baked in by build.rs.
+#[query]
+fn version() -> VersionInfo {
+    VersionInfo {
+        crate_version: env!("CARGO_PKG_VERSION").into(),
+        git_commit: option_env!("IC_CHESS_GIT_COMMIT").map(String::from),
+        build_time: option_env!("IC_CHESS_BUILD_TIME").map(String::from),
+    }
+}
+
+#[query]
+fn get_game(id: u64) -> Option<GameView> {
+    STATE.with(|s| s.borrow().games.get(&id).map(to_view))
+}
+
+/// Spectator-safe view: no principals. Seated players keep using `get_game`.
+#[query]
+fn public_view(game_id: u64) -> Option<PublicGameView> {
+    STATE.with(|s| s.borrow().games.get(&game_id).map(to_public_view))
+}
+
+/// Current FEN of each game, in input order (`None` for unknown ids). Only the
+/// first `MAX_BATCH` ids are looked up.
+#[query]
+fn fens(ids: Vec<u64>) -> Vec<(u64, Option<String>)> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        ids.into_iter()
+            .take(MAX_BATCH)
+            .map(|id| {
+                let fen = st.games.get(&id)
+                    .map(|g| Fen::from_position(&g.pos, EnPassantMode::Legal).to_string());
+                (id, fen)
+            })
+            .collect()
+    })
+}
+
+/// Bring a client holding `known_ply` moves at version `known_version` up to
+/// date: nothing, the missing moves, or the full view if its ply is past the
+/// end of the history. `None` for unknown games.
+#[query]
+fn sync(game_id: u64, known_ply: u32, known_version: u64) -> Option<SyncResult> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let g = st.games.get(&game_id)?;
+        let known_ply = known_ply as usize;
+        Some(if known_ply > g.moves_san.len() {
+            SyncResult::FullResync(Box::new(to_view(g)))
+        } else if known_ply == g.moves_san.len() && known_version == g.updated_ns {
+            SyncResult::UpToDate
+        } else {
+            SyncResult::Moves(g.moves_san[known_ply..].to_vec(), g.updated_ns)
+        })
+    })
+}
+
+/// Last `count` SAN moves (the whole list if the game is shorter).
+#[query]
+fn recent_moves(game_id: u64, count: u32) -> Vec<String> {
+    STATE.with(|s| {
+        s.borrow().games.get(&game_id).map(|g| {
+            let n = (count as usize).min(g.moves_san.len());
+            g.moves_san[g.moves_san.len() - n..].to_vec()
+        }).unwrap_or_default()
+    })
+}
+
+/// (move_number, white_san, black_san) rows as on a paper scoresheet. A game
+/// started from a FEN with black to move opens with an empty white cell.
+/// Empty for unknown games.
+#[query]
+fn scoresheet(game_id: u64) -> Vec<(u32, Option<String>, Option<String>)> {
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let Some(g) = binding.games.get(&game_id) else { return vec![] };
+        let Ok(start) = parse_fen(&g.start_fen) else { return vec![] };
+        let mut plies: Vec<Option<String>> = g.moves_san.iter().cloned().map(Some).collect();
+        if start.turn() == Color::Black {
+            plies.insert(0, None);
+        }
+        plies.chunks(2)
+            .zip(start.fullmoves().get()..)
+            .map(|(pair, n)| (n, pair[0].clone(), pair.get(1).cloned().flatten()))
+            .collect()
+    })
+}
+
+/// Integrity check: replaying `moves_san` must land on the stored position.
+#[query]
+fn verify_game(game_id: u64) -> Result<(), String> {
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let g = binding.games.get(&game_id).ok_or("No such game")?;
+        let pos = replay(g, g.moves_san.len())?;
+        let replayed = Fen::from_position(&pos, EnPassantMode::Legal).to_string();
+        let stored = Fen::from_position(&g.pos, EnPassantMode::Legal).to_string();
+        if replayed != stored {
+            return Err(format!("Replayed FEN {} differs from stored FEN {}", replayed, stored));
+        }
+        Ok(())
+    })
+}
+
+/// Analysis board: FEN after rewinding to `from_ply` and playing `line` (SAN or
+/// UCI) from there. Stored state is untouched; at most `MAX_BATCH` moves.
+#[query]
+fn explore(game_id: u64, from_ply: u32, line: Vec<String>) -> Result<String, String> {
+    if line.len() > MAX_BATCH {
+        return Err(format!("At most {} moves per line", MAX_BATCH));
+    }
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let g = binding.games.get(&game_id).ok_or("No such game")?;
+        if from_ply as usize > g.moves_san.len() {
+            return Err("No move at that ply".into());
+        }
+        let mut pos = replay(g, from_ply as usize)?;
+        for (i, mv) in line.iter().enumerate() {
+            let m = parse_move_with_autopromo(&pos, mv, g.auto_queen)
+                .map_err(|e| format!("Move {} ({}): {}", i, mv, e))?;
+            pos.play_unchecked(m);
+        }
+        Ok(Fen::from_position(&pos, EnPassantMode::Legal).to_string())
+    })
+}
+
+/// Stateless rules check: FEN after playing `uci` (SAN also accepted) on
+/// `fen`. Errors say whether the FEN or the move was at fault.
+#[query]
+fn apply_move_to_fen(fen: String, uci: String) -> Result<String, String> {
+    let pos = parse_fen(&fen).map_err(|e| format!("Bad FEN: {}", e))?;
+    let m = parse_move_with_autopromo(&pos, &uci, true)?;
+    let pos = pos.play(m).map_err(|_| "Illegal move")?;
+    Ok(Fen::from_position(&pos, EnPassantMode::Legal).to_string())
+}
+
+/// FEN of every position in the game, starting position first. Only the first
+/// `MAX_PAGE` positions are returned so long games stay within the response
+/// limit. Empty for unknown games.
+#[query]
+fn export_epd(game_id: u64) -> Vec<String> {
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let Some(g) = binding.games.get(&game_id) else { return vec![] };
+        let plies = g.moves_san.len().min(MAX_PAGE as usize - 1);
+        let mut out = Vec::with_capacity(plies + 1);
+        let Ok(last) = replay_with(g, plies, |pos, _| {
+            out.push(Fen::from_position(pos, EnPassantMode::Legal).to_string());
+        }) else {
+            return vec![];
+        };
+        out.push(Fen::from_position(&last, EnPassantMode::Legal).to_string());
+        out
+    })
+}
+
+/// True if some legal move from `from` to `to` promotes, i.e. the UI should
+/// show the piece picker. Bad squares or unknown games give false.
+#[query]
+fn is_promotion(game_id: u64, from: String, to: String) -> bool {
+    let (Ok(from), Ok(to)) = (Square::from_str(&from), Square::from_str(&to)) else {
+        return false;
+    };
+    STATE.with(|s| {
+        s.borrow().games.get(&game_id).is_some_and(|g| {
+            g.pos.legal_moves().iter()
+                .any(|m| m.from() == Some(from) && m.to() == to && m.promotion().is_some())
+        })
+    })
+}
+
+/// Whether any piece of the given color attacks `square` (e.g. "f7") right now,
+/// pins ignored. `None` for a bad square or unknown game.
+#[query]
+fn is_attacked(game_id: u64, square: String, by_white: bool) -> Option<bool> {
+    let sq = Square::from_str(&square).ok()?;
+    let attacker = if by_white { Color::White } else { Color::Black };
+    STATE.with(|s| {
+        let st = s.borrow();
+        let board = st.games.get(&game_id)?.pos.board();
+        Some(board.attacks_to(sq, attacker, board.occupied()).any())
+    })
+}
+
+/// Perft from the current position, for checking the move generator against a
+/// reference engine. `depth` is capped at `MAX_PERFT_DEPTH`.
+#[query]
+fn perft(game_id: u64, depth: u8) -> Result<u64, String> {
+    if depth > MAX_PERFT_DEPTH {
+        return Err(format!("Depth at most {}", MAX_PERFT_DEPTH));
+    }
+    STATE.with(|s| {
+        let st = s.borrow();
+        let g = st.games.get(&game_id).ok_or("No such game")?;
+        Ok(perft_count(&g.pos, depth))
+    })
+}
+
+/// The game in Lichess's shape (see `LichessGame`). `None` for unknown games.
+#[query]
+fn lichess_style(game_id: u64) -> Option<LichessGame> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let g = st.games.get(&game_id)?;
+        let mut last_move = None;
+        replay_with(g, g.moves_san.len(), |_, m| {
+            last_move = Some(m.to_uci(CastlingMode::Standard).to_string());
+        }).ok()?;
+        let player = |color: &str, seat: Option<Principal>, guest: &Option<String>| LichessPlayer {
+            color: color.into(),
+            name: seat.map(|p| p.to_text()).or_else(|| guest.clone()),
+            rating: None,
+        };
+        let status = match g.status {
+            GameStatus::Ongoing => "started",
+            GameStatus::Checkmate { .. } => "mate",
+            GameStatus::Resigned { .. } => "resign",
+            GameStatus::Timeout { .. } => "timeout",
+            // Lichess has no illegal-move forfeit
+            GameStatus::Forfeit { .. } => "unknownFinish",
+            GameStatus::Stalemate => "stalemate",
+            GameStatus::Draw { .. } => "draw",
+            GameStatus::VariantWin { .. } => "variantEnd",
+        };
+        let winner = match outcome(&g.status) {
+            Some(Outcome::WhiteWins) => Some("white".to_string()),
+            Some(Outcome::BlackWins) => Some("black".to_string()),
+            _ => None,
+        };
+        Some(LichessGame {
+            id: g.id.to_string(),
+            players: vec![
+                player("white", g.white, &g.white_guest),
+                player("black", g.black, &g.black_guest),
+            ],
+            fen: Fen::from_position(&g.pos, EnPassantMode::Legal).to_string(),
+            last_move,
+            status: status.into(),
+            winner,
+        })
+    })
+}
+
+/// `material_balance` after every ply, for an advantage-over-time chart. Scans
+/// the whole game; empty for unknown games.
+#[query]
+fn eval_graph(game_id: u64) -> Vec<i32> {
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let Some(g) = binding.games.get(&game_id) else { return vec![] };
+        let mut out = Vec::with_capacity(g.moves_san.len());
+        replay_with(g, g.moves_san.len(), |pos, m| {
+            let mut after = pos.clone();
+            after.play_unchecked(m);
+            out.push(material_balance(&after));
+        }).map(|_| out).unwrap_or_default()
+    })
+}
+
+/// Number of legal moves for the side to move; 0 once the game is over.
+#[query]
+fn legal_move_count(game_id: u64) -> u32 {
+    STATE.with(|s| {
+        match s.borrow().games.get(&game_id) {
+            Some(g) if matches!(g.status, GameStatus::Ongoing) => g.pos.legal_moves().len() as u32,
+            _ => 0,
+        }
+    })
+}
+
+/// Current board as piece bitboards (see `Bitboards` for square numbering).
+#[query]
+fn bitboards(game_id: u64) -> Option<Bitboards> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let board = st.games.get(&game_id)?.pos.board().clone();
+        let bb = |color, role| u64::from(board.by_color(color) & board.by_role(role));
+        Some(Bitboards {
+            white_pawns: bb(Color::White, Role::Pawn),
+            white_knights: bb(Color::White, Role::Knight),
+            white_bishops: bb(Color::White, Role::Bishop),
+            white_rooks: bb(Color::White, Role::Rook),
+            white_queens: bb(Color::White, Role::Queen),
+            white_kings: bb(Color::White, Role::King),
+            black_pawns: bb(Color::Black, Role::Pawn),
+            black_knights: bb(Color::Black, Role::Knight),
+            black_bishops: bb(Color::Black, Role::Bishop),
+            black_rooks: bb(Color::Black, Role::Rook),
+            black_queens: bb(Color::Black, Role::Queen),
+            black_kings: bb(Color::Black, Role::King),
+        })
+    })
+}
+
+/// Endgame once non-pawn material drops to `ENDGAME_MATERIAL`, else opening
+/// through move `OPENING_FULLMOVES`, else middlegame. `None` for unknown games.
+#[query]
+fn game_phase(game_id: u64) -> Option<Phase> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let pos = &st.games.get(&game_id)?.pos;
+        let board = pos.board();
+        let material: u32 = [(Role::Knight, 3), (Role::Bishop, 3), (Role::Rook, 5), (Role::Queen, 9)]
+            .into_iter()
+            .map(|(role, value)| board.by_role(role).count() as u32 * value)
+            .sum();
+        Some(if material <= ENDGAME_MATERIAL {
+            Phase::Endgame
+        } else if pos.fullmoves().get() <= OPENING_FULLMOVES {
+            Phase::Opening
+        } else {
+            Phase::Middlegame
+        })
+    })
+}
+
+/// All false for unknown or finished games.
+#[query]
+fn castling_options(game_id: u64) -> CastlingOptions {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let Some(g) = st.games.get(&game_id) else { return CastlingOptions::default() };
+        if !matches!(g.status, GameStatus::Ongoing) {
+            return CastlingOptions::default();
+        }
+        // the other side only gets a turn if it isn't giving check right now
+        let other = g.pos.clone().swap_turn().ok();
+        let (white, black) = match g.pos.turn() {
+            Color::White => (Some(&g.pos), other.as_ref()),
+            Color::Black => (other.as_ref(), Some(&g.pos)),
+        };
+        let can = |pos: Option<&Chess>, side| {
+            pos.is_some_and(|p| p.legal_moves().iter().any(|m| m.castling_side() == Some(side)))
+        };
+        CastlingOptions {
+            white_king_side: can(white, CastlingSide::KingSide),
+            white_queen_side: can(white, CastlingSide::QueenSide),
+            black_king_side: can(black, CastlingSide::KingSide),
+            black_queen_side: can(black, CastlingSide::QueenSide),
+        }
+    })
+}
+
+/// Legal moves for the side to move in SAN, in `legal_moves()` order; empty
+/// once the game is over.
+#[query]
+fn legal_moves_san(game_id: u64) -> Vec<String> {
+    STATE.with(|s| {
+        match s.borrow().games.get(&game_id) {
+            Some(g) if matches!(g.status, GameStatus::Ongoing) => g.pos.legal_moves().iter()
+                .map(|m| San::from_move(&g.pos, *m).to_string())
+                .collect(),
+            _ => vec![],
+        }
+    })
+}
+
+/// Legal UCI moves of one piece type, named in full ("knight") or by letter
+/// ("n"), case-insensitively. Empty for unknown roles, games, or finished games.
+#[query]
+fn legal_moves_for_role(game_id: u64, role: String) -> Vec<String> {
+    let role = match role.trim().to_ascii_lowercase().as_str() {
+        "pawn" | "p" => Role::Pawn,
+        "knight" | "n" => Role::Knight,
+        "bishop" | "b" => Role::Bishop,
+        "rook" | "r" => Role::Rook,
+        "queen" | "q" => Role::Queen,
+        "king" | "k" => Role::King,
+        _ => return vec![],
+    };
+    STATE.with(|s| {
+        match s.borrow().games.get(&game_id) {
+            Some(g) if matches!(g.status, GameStatus::Ongoing) => g.pos.legal_moves().iter()
+                .filter(|m| m.role() == role)
+                .map(|m| m.to_uci(CastlingMode::Standard).to_string())
+                .collect(),
+            _ => vec![],
+        }
+    })
+}
+
+#[query]
+fn list_recent(offset_desc: u64, limit: u32) -> Vec<GameView> {
+    STATE.with(|s| {
+        let games = &s.borrow().games;
+        let mut ids: Vec<_> = games.keys().cloned().collect();
+        ids.sort_unstable_by(|a,b| b.cmp(a)); // newest first
+        ids.into_iter()
+            .skip(offset_desc as usize)
+            .take(limit as usize)
+            .filter_map(|id| games.get(&id).map(to_view))
+            .collect()
+    })
+}
+
+/// Moves played in this canister, across all games.
+#[query]
+fn total_moves_played() -> u64 {
+    STATE.with(|s| s.borrow().total_moves)
+}
+
+/// Games created in `[from_ns, to_ns)`, newest first. `limit` is capped at
+/// `MAX_PAGE`.
+#[query]
+fn games_in_range(from_ns: u64, to_ns: u64, limit: u32) -> Vec<GameView> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        // imported games keep their original created_ns, so ids aren't enough
+        let mut hits: Vec<_> = st.games.values()
+            .filter(|g| (from_ns..to_ns).contains(&g.created_ns))
+            .collect();
+        hits.sort_by_key(|g| std::cmp::Reverse(g.created_ns));
+        hits.into_iter()
+            .take(limit.min(MAX_PAGE) as usize)
+            .map(to_view)
+            .collect()
+    })
+}
+
+/// Distinct principals that have held a seat in any game, in principal order.
+/// Paginated; `limit` is capped at `MAX_PAGE`.
+#[query]
+fn all_players(offset: u64, limit: u32) -> Vec<Principal> {
+    STATE.with(|s| {
+        let players: BTreeSet<Principal> = s.borrow().games.values()
+            .flat_map(|g| [g.white, g.black])
+            .flatten()
+            .collect();
+        players.into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE) as usize)
+            .collect()
+    })
+}
+
+/// Finished games of `p`, by how they ended and from `p`'s side.
+#[query]
+fn result_breakdown(p: Principal) -> ResultBreakdown {
+    STATE.with(|s| {
+        let mut out = ResultBreakdown::default();
+        for g in s.borrow().games.values() {
+            let is_white = if g.white == Some(p) {
+                true
+            } else if g.black == Some(p) {
+                false
+            } else {
+                continue;
+            };
+            match g.status {
+                GameStatus::Ongoing => {}
+                GameStatus::Checkmate { winner_white } if winner_white == is_white => out.checkmate_wins += 1,
+                GameStatus::Checkmate { .. } => out.checkmate_losses += 1,
+                GameStatus::Resigned { winner_white } if winner_white == is_white => out.resign_wins += 1,
+                GameStatus::Resigned { .. } => out.resign_losses += 1,
+                GameStatus::Timeout { winner_white } if winner_white == is_white => out.timeout_wins += 1,
+                GameStatus::Timeout { .. } => out.timeout_losses += 1,
+                GameStatus::Forfeit { winner_white } if winner_white == is_white => out.forfeit_wins += 1,
+                GameStatus::Forfeit { .. } => out.forfeit_losses += 1,
+                GameStatus::VariantWin { winner_white, .. } if winner_white == is_white => out.variant_wins += 1,
+                GameStatus::VariantWin { .. } => out.variant_losses += 1,
+                GameStatus::Stalemate | GameStatus::Draw { .. } => out.draws += 1,
+            }
+        }
+        out
+    })
+}
+
+/// `p`'s current run of identical results, newest finished game first:
+/// ("win" | "loss" | "draw", length), or ("none", 0) without finished games.
+#[query]
+fn current_streak(p: Principal) -> (String, u32) {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let mut results: Vec<(u64, &'static str)> = st.games.values()
+            .filter_map(|g| {
+                let is_white = if g.white == Some(p) {
+                    true
+                } else if g.black == Some(p) {
+                    false
+                } else {
+                    return None;
+                };
+                let result = match (outcome(&g.status)?, is_white) {
+                    (Outcome::Draw, _) => "draw",
+                    (Outcome::WhiteWins, true) | (Outcome::BlackWins, false) => "win",
+                    _ => "loss",
+                };
+                Some((g.finished_ns.unwrap_or(g.updated_ns), result))
+            })
+            .collect();
+        results.sort_by_key(|(ended, _)| std::cmp::Reverse(*ended));
+        let Some((_, kind)) = results.first().copied() else {
+            return ("none".to_string(), 0);
+        };
+        let len = results.iter().take_while(|(_, r)| *r == kind).count() as u32;
+        (kind.to_string(), len)
+    })
+}
+
+/// (player, wins, losses, draws, score)
+type CrosstableRow = (Principal, u32, u32, u32, f64);
+
+/// Standings over a set of finished games: (player, wins, losses, draws, score)
+/// with 1 / 0.5 / 0 scoring, highest score first. Ongoing games are skipped and
+/// duplicate ids count once. At most `MAX_BATCH` ids.
+#[query]
+fn crosstable(game_ids: Vec<u64>) -> Result<Vec<CrosstableRow>, String> {
+    if game_ids.len() > MAX_BATCH {
+        return Err(format!("At most {} games per call", MAX_BATCH));
+    }
+    let ids: BTreeSet<u64> = game_ids.into_iter().collect();
+    STATE.with(|s| {
+        let st = s.borrow();
+        let mut table: BTreeMap<Principal, (u32, u32, u32)> = BTreeMap::new();
+        for g in ids.iter().filter_map(|id| st.games.get(id)) {
+            let Some(result) = outcome(&g.status) else { continue };
+            for (seat, is_white) in [(g.white, true), (g.black, false)] {
+                let Some(p) = seat else { continue };
+                let row = table.entry(p).or_default();
+                match (result, is_white) {
+                    (Outcome::Draw, _) => row.2 += 1,
+                    (Outcome::WhiteWins, true) | (Outcome::BlackWins, false) => row.0 += 1,
+                    _ => row.1 += 1,
+                }
+            }
+        }
+        let mut rows: Vec<_> = table.into_iter()
+            .map(|(p, (w, l, d))| (p, w, l, d, w as f64 + d as f64 / 2.0))
+            .collect();
+        rows.sort_by(|a, b| b.4.total_cmp(&a.4));
+        Ok(rows)
+    })
+}
+
+/// Ongoing games where the caller holds the seat of the side to move, oldest first.
+#[query]
+fn my_turn_games() -> Vec<GameView> {
+    let who = caller();
+    STATE.with(|s| {
+        s.borrow().games.values()
+            .filter(|g| matches!(g.status, GameStatus::Ongoing))
+            .filter(|g| match g.pos.turn() {
+                Color::White => g.white == Some(who),
+                Color::Black => g.black == Some(who),
+            })
+            .map(to_view)
+            .collect()
+    })
+}
+
+/// Piece placement only (the first FEN field), for thumbnails.
+#[query]
+fn board_fen(game_id: u64) -> Option<String> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let fen = Fen::from_position(&st.games.get(&game_id)?.pos, EnPassantMode::Legal).to_string();
+        fen.split_whitespace().next().map(String::from)
+    })
+}
+
+/// Everything a share link needs. `None` for unknown games.
+#[query]
+fn share_info(game_id: u64) -> Option<ShareInfo> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let g = st.games.get(&game_id)?;
+        Some(ShareInfo {
+            game_id,
+            fen: Fen::from_position(&g.pos, EnPassantMode::Legal).to_string(),
+            ply: g.moves_san.len() as u32,
+            status_text: status_text(&g.status),
+        })
+    })
+}
+
+/// Half-move clock and repetition counts, for draw warnings. `None` for
+/// unknown games.
+#[query]
+fn rule_state(game_id: u64) -> Option<RuleState> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let g = st.games.get(&game_id)?;
+        Some(RuleState {
+            halfmove_clock: g.pos.halfmoves(),
+            current_repetition: g.repetitions.get(&position_key(&g.pos)).copied().unwrap_or(1),
+            max_repetition: g.repetitions.values().copied().max().unwrap_or(1),
+        })
+    })
+}
+
+/// Whether the caller could `claim_draw` right now: seated in an ongoing game
+/// whose position has occurred three times or where the fifty-move rule applies.
+#[query]
+fn can_claim_draw(game_id: u64) -> bool {
+    let who = caller();
+    STATE.with(|s| {
+        s.borrow().games.get(&game_id).is_some_and(|g| {
+            matches!(g.status, GameStatus::Ongoing)
+                && is_seated(g, who)
+                && (threefold_holds(g) || fifty_move_holds(&g.pos))
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn my_role(game_id: u64) -> PlayerRole {
+    STATE.with(|s| {
+        let who = caller();
+        if let Some(g) = s.borrow().games.get(&game_id) {
+            if g.white == Some(who) {
                 PlayerRole::White
             } else if g.black == Some(who) {
                 PlayerRole::Black
@@ -208,162 +2046,1334 @@ fn my_role(game_id: u64) -> PlayerRole {
                 PlayerRole::Spectator
             }
         } else {
-            PlayerRole::Spectator
+            PlayerRole::Spectator
+        }
+    })
+}
+
+/// Seats whose token can still be redeemed. Empty once both players have
+/// joined or the game is over. In random-color games the roles only say which
+/// token is unused, not which color it will get.
+#[query]
+fn open_seats(game_id: u64) -> Vec<PlayerRole> {
+    STATE.with(|s| {
+        let st = s.borrow();
+        let Some(g) = st.games.get(&game_id) else { return vec![] };
+        if !matches!(g.status, GameStatus::Ongoing) {
+            return vec![];
+        }
+        let mut out = vec![];
+        if g.white.is_none() && g.white_guest.is_none() && g.white_token_hash != [0u8; 32] {
+            out.push(PlayerRole::White);
+        }
+        if g.black.is_none() && g.black_guest.is_none() && g.black_token_hash != [0u8; 32] {
+            out.push(PlayerRole::Black);
+        }
+        out
+    })
+}
+
+/// (white, black, white_token_hash, black_token_hash)
+type DebugSeats = (Option<Principal>, Option<Principal>, [u8; 32], [u8; 32]);
+
+/// Principal in the seat opposite the caller; `None` if the caller isn't seated
+/// or the other seat is still empty.
+#[query]
+fn opponent(game_id: u64) -> Option<Principal> {
+    let who = caller();
+    STATE.with(|s| {
+        let st = s.borrow();
+        let g = st.games.get(&game_id)?;
+        if g.white == Some(who) {
+            g.black
+        } else if g.black == Some(who) {
+            g.white
+        } else {
+            None
+        }
+    })
+}
+
+/// Debug helper to inspect seats and token hashes (for testing)
+#[ic_cdk::query]
+fn debug_game(game_id: u64) -> Option<DebugSeats> {
+    STATE.with(|s| {
+        s.borrow().games.get(&game_id).map(|g| {
+            (g.white, g.black, g.white_token_hash, g.black_token_hash)
+        })
+    })
+}
+
+/// Comments attached to moves, ordered by ply.
+#[query]
+fn get_annotations(game_id: u64) -> Vec<(u32, String)> {
+    STATE.with(|s| {
+        s.borrow().games.get(&game_id)
+            .map(|g| g.move_comments.iter().map(|(p, c)| (*p, c.clone())).collect())
+            .unwrap_or_default()
+    })
+}
+
+// -------------------- Updates --------------------
+
+/// Create a new game. Returns (game_id, white_token, black_token).
+#[update]
+async fn create_game() -> (u64, String, String) {
+    let (white_token, black_token) = token_pair().await;
+    let id = insert_game(new_game(Chess::default(), &white_token, &black_token));
+    (id, white_token, black_token)
+}
+
+/// `create_game` that also returns the starting view, so the client can render
+/// the board without a follow-up `get_game`.
+#[update]
+async fn create_game_v2() -> CreateResult {
+    let (white_token, black_token) = token_pair().await;
+    let id = insert_game(new_game(Chess::default(), &white_token, &black_token));
+    let view = STATE.with(|s| to_view(&s.borrow().games[&id]));
+    CreateResult { id, white_token, black_token, view }
+}
+
+/// `create_game` with per-game settings. Returns (game_id, white_token, black_token).
+#[update]
+async fn create_game_with_options(opts: GameOptions) -> (u64, String, String) {
+    let (white_token, black_token) = token_pair().await;
+    let mut g = new_game(Chess::default(), &white_token, &black_token);
+    apply_options(&mut g, &opts);
+    (insert_game(g), white_token, black_token)
+}
+
+/// Idempotent `create_game`: the first call with `key` creates a game and returns
+/// (game_id, Some((white_token, black_token))); later calls return the same id
+/// with `None`, since tokens are only ever handed out once.
+#[update]
+async fn create_game_with_key(key: String) -> Result<(u64, Option<(String, String)>), String> {
+    if key.is_empty() || key.len() > MAX_GAME_KEY_LEN {
+        return Err(format!("Key must be 1-{} bytes", MAX_GAME_KEY_LEN));
+    }
+    if let Some(id) = STATE.with(|s| s.borrow().keys.get(&key).copied()) {
+        return Ok((id, None));
+    }
+    let (white_token, black_token) = token_pair().await;
+    // another call may have claimed the key while we awaited randomness
+    if let Some(id) = STATE.with(|s| s.borrow().keys.get(&key).copied()) {
+        return Ok((id, None));
+    }
+    let id = insert_game(new_game(Chess::default(), &white_token, &black_token));
+    STATE.with(|s| s.borrow_mut().keys.insert(key, id));
+    Ok((id, Some((white_token, black_token))))
+}
+
+/// Create a game from a custom starting position. The FEN's halfmove clock and
+/// fullmove number carry over. Returns (game_id, white_token, black_token).
+#[update]
+async fn create_game_from_fen(fen: String) -> Result<(u64, String, String), String> {
+    let pos = parse_fen(&fen)?;
+    let (white_token, black_token) = token_pair().await;
+    let id = insert_game(new_game(pos, &white_token, &black_token));
+    Ok((id, white_token, black_token))
+}
+
+/// Check that `solution_uci` plays legally from `start`, normalized to standard
+/// UCI as `try_puzzle_move` compares it.
+fn puzzle_solution(start: &Chess, solution_uci: &[String]) -> Result<Vec<String>, String> {
+    let mut pos = start.clone();
+    let mut solution = Vec::with_capacity(solution_uci.len());
+    for (i, uci) in solution_uci.iter().enumerate() {
+        let m = parse_uci_to_move(&pos, uci).ok_or_else(|| format!("Solution move {} ({}) is illegal", i, uci))?;
+        solution.push(m.to_uci(CastlingMode::Standard).to_string());
+        pos.play_unchecked(m);
+    }
+    Ok(solution)
+}
+
+/// Store a puzzle: the side to move in `fen` must find `solution_uci`, whose
+/// odd entries are the opponent's replies. Only the creator can solve it, via
+/// `try_puzzle_move`. Returns the game id.
+#[update]
+fn create_puzzle(fen: String, solution_uci: Vec<String>) -> Result<u64, String> {
+    if solution_uci.is_empty() || solution_uci.len() > MAX_BATCH {
+        return Err(format!("Solution must have 1-{} moves", MAX_BATCH));
+    }
+    let start = parse_fen(&fen)?;
+    let solution = puzzle_solution(&start, &solution_uci)?;
+    let mut g = new_game(start, "", "");
+    g.white_token_hash = [0u8; 32];
+    g.black_token_hash = [0u8; 32];
+    g.puzzle_solution = Some(solution);
+    Ok(insert_game(g))
+}
+
+/// Controller-only: provision `n` games (at most `MAX_BULK_CREATE`) in one call,
+/// e.g. a tournament round. Returns (game_id, white_token, black_token) per game.
+#[update]
+async fn create_games(n: u32) -> Result<Vec<(u64, String, String)>, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can create games in bulk".into());
+    }
+    if n > MAX_BULK_CREATE {
+        return Err(format!("At most {} games per call", MAX_BULK_CREATE));
+    }
+    let mut out = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let (white_token, black_token) = token_pair().await;
+        let id = insert_game(new_game(Chess::default(), &white_token, &black_token));
+        out.push((id, white_token, black_token));
+    }
+    Ok(out)
+}
+
+/// Controller-only: create a game with both seats already filled, e.g. by a
+/// trusted matchmaking service. No tokens are issued.
+#[update]
+fn create_game_seated(white: Principal, black: Principal) -> Result<u64, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can create seated games".into());
+    }
+    if white == black {
+        return Err("White and black must be different principals".into());
+    }
+    let mut g = new_game(Chess::default(), "", "");
+    g.white_token_hash = [0u8; 32];
+    g.black_token_hash = [0u8; 32];
+    g.white = Some(white);
+    g.black = Some(black);
+    g.white_joined_ns = Some(g.created_ns);
+    g.black_joined_ns = Some(g.created_ns);
+    Ok(insert_game(g))
+}
+
+/// Controller-only: create a game and play uniformly random legal moves for
+/// both sides until it ends (a draw is declared after `MAX_SIMULATED_PLIES`).
+/// Seats stay empty and no tokens are issued. Returns the game id.
+#[update]
+async fn simulate_random_game() -> Result<u64, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can simulate games".into());
+    }
+    let seed = raw_rand().await.map_err(|_| "raw_rand failed")?;
+    let mut g = new_game(Chess::default(), "", "");
+    g.white_token_hash = [0u8; 32];
+    g.black_token_hash = [0u8; 32];
+    for ply in 0..MAX_SIMULATED_PLIES {
+        if !matches!(g.status, GameStatus::Ongoing) {
+            break;
+        }
+        // one raw_rand call seeds the whole game: hash(seed, ply) picks each move
+        let mut h = Sha256::new();
+        h.update(&seed);
+        h.update((ply as u64).to_le_bytes());
+        let r = u64::from_le_bytes(h.finalize()[..8].try_into().unwrap());
+        let moves = g.pos.legal_moves();
+        let m = moves[(r % moves.len() as u64) as usize];
+        play_parsed_move(&mut g, m, time())?;
+    }
+    if matches!(g.status, GameStatus::Ongoing) {
+        g.status = GameStatus::Draw { reason: "simulation move limit".into() };
+        g.finished_ns = Some(g.last_move_ns);
+    }
+    let plies = g.moves_san.len() as u64;
+    let id = insert_game(g);
+    STATE.with(|s| s.borrow_mut().total_moves += plies);
+    Ok(id)
+}
+
+/// Like `create_game`, but the tokens don't fix a color: when the second
+/// player joins, the backend flips a coin (`raw_rand`) to decide who is white.
+#[update]
+async fn create_game_random_colors() -> (u64, String, String) {
+    let (token_a, token_b) = token_pair().await;
+    let mut g = new_game(Chess::default(), &token_a, &token_b);
+    g.random_colors = true;
+    (insert_game(g), token_a, token_b)
+}
+
+/// Claim a seat using a one-time token (burned on success)
+#[ic_cdk::update]
+async fn join_by_token(game_id: u64, token: String) -> Result<GameView, String> {
+    // Random-color games need a coin flip when the second player arrives;
+    // fetch it before touching state so nothing is borrowed across the await.
+    let who = caller();
+    let pairing = STATE.with(|s| {
+        s.borrow().games.get(&game_id).is_some_and(|g| g.random_colors && g.first_joiner.is_some())
+    });
+    if pairing {
+        // only a caller under the failure limit with a valid token may cost a raw_rand call
+        STATE.with(|s| limit_failed_joins(&mut s.borrow_mut(), who, |st| {
+            check_random_seat(st.games.get(&game_id).ok_or("No such game")?, who, &token)
+        }))?;
+    }
+    let white_first = if pairing {
+        let bytes = raw_rand().await.map_err(|_| "raw_rand failed")?;
+        Some(bytes[0] & 1 == 0)
+    } else {
+        None
+    };
+    STATE.with(|s| {
+        limit_failed_joins(&mut s.borrow_mut(), who, |st| match st.games.get_mut(&game_id) {
+            None => Err("No such game".into()),
+            Some(g) if g.random_colors => claim_random_seat(g, who, &token, white_first).map(|_| to_view(g)),
+            Some(g) => claim_seat(g, who, &token).map(|_| to_view(g)),
+        })
+    })
+}
+
+/// Take a seat as a named guest (no principal), e.g. two players sharing one
+/// device. The token is not burned: it is the guest's credential for `guest_move`.
+#[update]
+fn join_as_guest(game_id: u64, token: String, guest_name: String) -> Result<GameView, String> {
+    let name = guest_name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_GUEST_NAME_LEN {
+        return Err(format!("Guest name must be 1-{} bytes", MAX_GUEST_NAME_LEN));
+    }
+    STATE.with(|s| limit_failed_joins(&mut s.borrow_mut(), caller(), |st| {
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if g.random_colors {
+            return Err("Guests cannot join random-color games".into());
+        }
+        let th = hash_token(&token);
+        if token_matches(th, g.white_token_hash) {
+            if g.white.is_some() || g.white_guest.is_some() {
+                return Err("White seat already taken".into());
+            }
+            g.white_guest = Some(name);
+            g.white_joined_ns = Some(time());
+        } else if token_matches(th, g.black_token_hash) {
+            if g.black.is_some() || g.black_guest.is_some() {
+                return Err("Black seat already taken".into());
+            }
+            g.black_guest = Some(name);
+            g.black_joined_ns = Some(time());
+        } else {
+            return Err("Invalid or already-used token".into());
+        }
+        g.updated_ns = time();
+        Ok(to_view(g))
+    }))
+}
+
+/// Move for a guest seat; `token` must be that seat's token. In
+/// `confirm_moves` games the move is only staged until `guest_confirm_move`.
+#[update]
+fn guest_move(game_id: u64, token: String, mv: String) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        authorize_guest_move(g, &token)?;
+        let played = submit_move(g, &mv).map_err(|e| note_illegal_attempt(g, e))?;
+        let view = to_view(g);
+        if played {
+            st.total_moves += 1;
+        }
+        Ok(view)
+    })
+}
+
+/// Play the move a guest staged with `guest_move`; `token` as for `guest_move`.
+#[update]
+fn guest_confirm_move(game_id: u64, token: String) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        authorize_guest_move(g, &token)?;
+        let m = g.pending_move.ok_or("No move to confirm")?;
+        play_parsed_move(g, m, time())?;
+        let view = to_view(g);
+        st.total_moves += 1;
+        Ok(view)
+    })
+}
+
+/// `client_move_id` makes retries safe: resubmitting the id of the last applied
+/// move returns the current view instead of an error. In `confirm_moves` games
+/// the move is only staged (replacing any earlier one) until `confirm_move`.
+#[update]
+fn make_move(game_id: u64, mv: String, client_move_id: Option<u64>) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if client_move_id.is_some() && client_move_id == g.last_client_move_id {
+            return Ok(to_view(g));
+        }
+        authorize_move(g, who)?;
+        if !submit_move(g, &mv).map_err(|e| note_illegal_attempt(g, e))? {
+            return Ok(to_view(g));
+        }
+        g.last_client_move_id = client_move_id;
+        let view = to_view(g);
+        st.total_moves += 1;
+        Ok(view)
+    })
+}
+
+/// Play the move staged by `make_move` in a `confirm_moves` game.
+#[update]
+fn confirm_move(game_id: u64) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        authorize_move(g, who)?;
+        let m = g.pending_move.ok_or("No move to confirm")?;
+        play_parsed_move(g, m, time())?;
+        let view = to_view(g);
+        st.total_moves += 1;
+        Ok(view)
+    })
+}
+
+/// Discard the move staged by `make_move`.
+#[update]
+fn cancel_move(game_id: u64) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        authorize_move(g, who)?;
+        if g.pending_move.take().is_none() {
+            return Err("No move to cancel".into());
+        }
+        g.updated_ns = time();
+        Ok(to_view(g))
+    })
+}
+
+/// Claim a seat and, if that color is to move, play `first_move` in the same call
+/// (only staged in `confirm_moves` games). The seat claim persists even when the
+/// move is rejected; the move error comes back as the second tuple element.
+#[update]
+fn join_and_move(game_id: u64, token: String, first_move: String) -> Result<(GameView, Option<String>), String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let color = limit_failed_joins(&mut st, who, |st| {
+            let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+            claim_seat(g, who, &token)
+        })?;
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        let submitted = if g.pos.turn() != color {
+            Err("Not your turn".to_string())
+        } else {
+            authorize_move(g, who)
+                .and_then(|_| submit_move(g, &first_move).map_err(|e| note_illegal_attempt(g, e)))
+        };
+        let view = to_view(g);
+        if submitted == Ok(true) {
+            st.total_moves += 1;
+        }
+        Ok((view, submitted.err()))
+    })
+}
+
+/// Claim a draw by threefold repetition or the fifty-move rule in a game
+/// without `auto_draw`. Either seated player may claim once the current position
+/// has occurred three times or 50 moves have passed without a capture or pawn move.
+#[update]
+fn claim_draw(game_id: u64) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if !matches!(g.status, GameStatus::Ongoing) {
+            return Err("Game finished".into());
+        }
+        if !is_seated(g, who) {
+            return Err("You are not seated".into());
+        }
+        let reason = if threefold_holds(g) {
+            "threefold repetition"
+        } else if fifty_move_holds(&g.pos) {
+            "fifty-move rule"
+        } else {
+            return Err("Position has not occurred three times and the fifty-move rule does not apply".into());
+        };
+        g.status = GameStatus::Draw { reason: reason.into() };
+        g.pending_move = None;
+        g.updated_ns = time();
+        g.finished_ns = Some(g.updated_ns);
+        notify_result(g);
+        Ok(to_view(g))
+    })
+}
+
+/// Symmetric draw agreement: the first seated caller records intent and the
+/// opponent calling too ends the game drawn. A move in between cancels it.
+#[update]
+fn agree_draw(game_id: u64) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if !matches!(g.status, GameStatus::Ongoing) {
+            return Err("Game finished".into());
+        }
+        if !is_seated(g, who) {
+            return Err("You are not seated".into());
+        }
+        match g.draw_agree_from {
+            Some(p) if p == who => return Err("Waiting for your opponent to agree".into()),
+            Some(_) => {
+                g.status = GameStatus::Draw { reason: "agreement".into() };
+                g.draw_agree_from = None;
+                g.pending_move = None;
+                g.finished_ns = Some(time());
+                notify_result(g);
+            }
+            None => g.draw_agree_from = Some(who),
+        }
+        g.updated_ns = time();
+        Ok(to_view(g))
+    })
+}
+
+/// Win a game whose opponent, on move, has let `move_deadline_ns` pass since
+/// the last move. Only the waiting seated player can claim; a claimant without
+/// mating material gets a draw instead.
+#[update]
+fn claim_expired(game_id: u64) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        ensure_playable(g)?;
+        let deadline = g.move_deadline_ns.ok_or("This game has no move deadline")?;
+        let waiting = match g.pos.turn() {
+            Color::White => g.black,
+            Color::Black => g.white,
+        };
+        if waiting != Some(who) {
+            return Err("Only the player waiting for a move can claim".into());
+        }
+        if !seat_filled(g, g.pos.turn()) {
+            return Err("Opponent has not joined".into());
+        }
+        if !deadline_passed(g, deadline, time()) {
+            return Err("Move deadline has not passed".into());
+        }
+        let claimant = !g.pos.turn();
+        g.status = if cannot_win(&g.pos, claimant, g.variant) {
+            GameStatus::Draw { reason: "timeout against insufficient material".into() }
+        } else {
+            GameStatus::Timeout { winner_white: claimant == Color::White }
+        };
+        g.pending_move = None;
+        g.updated_ns = time();
+        g.finished_ns = Some(g.updated_ns);
+        notify_result(g);
+        Ok(to_view(g))
+    })
+}
+
+/// Attempt the next solution move of a puzzle. A correct move is played along
+/// with the puzzle's reply; a wrong one changes nothing.
+#[update]
+fn try_puzzle_move(game_id: u64, uci: String) -> Result<PuzzleResult, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if g.creator != who {
+            return Err("Only the creator can solve this puzzle".into());
+        }
+        let solution = g.puzzle_solution.clone().ok_or("Not a puzzle")?;
+        let ply = g.moves_san.len();
+        let Some(expected) = solution.get(ply) else {
+            return Err("Puzzle already solved".into());
+        };
+        let m = parse_move_with_autopromo(&g.pos, &uci, g.auto_queen)?;
+        if m.to_uci(CastlingMode::Standard).to_string() != *expected {
+            return Ok(PuzzleResult::Wrong);
+        }
+        play_parsed_move(g, m, time())?;
+        if let Some(reply) = solution.get(ply + 1) {
+            play_move(g, reply)?;
+        }
+        let played = (g.moves_san.len() - ply) as u64;
+        let result = if g.moves_san.len() >= solution.len() { PuzzleResult::Solved } else { PuzzleResult::Correct };
+        st.total_moves += played;
+        Ok(result)
+    })
+}
+
+/// Result of a resignation in favor of `winner_white`.
+fn resign_status(g: &GameInternal, winner_white: bool) -> GameStatus {
+    let winner = if winner_white { Color::White } else { Color::Black };
+    if g.fair_resign && cannot_win(&g.pos, winner, g.variant) {
+        GameStatus::Draw { reason: "resignation against insufficient material".into() }
+    } else {
+        GameStatus::Resigned { winner_white }
+    }
+}
+
+/// With `fair_resign`, resigning against a side that cannot mate is a draw.
+#[update]
+fn resign(game_id: u64) -> Result<GameView, String> {
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if !matches!(g.status, GameStatus::Ongoing) {
+            return Err("Game finished".into());
+        }
+        let winner_white = if g.white == Some(who) {
+            false
+        } else if g.black == Some(who) {
+            true
+        } else {
+            return Err("You are not seated".into());
+        };
+        g.status = resign_status(g, winner_white);
+        g.pending_move = None;
+        g.updated_ns = time();
+        g.finished_ns = Some(g.updated_ns);
+        notify_result(g);
+        Ok(to_view(g))
+    })
+}
+
+/// Attach a comment to the move at `ply` (0-based). An empty comment removes it.
+#[update]
+fn annotate_move(game_id: u64, ply: u32, comment: String) -> Result<(), String> {
+    if comment.len() > MAX_COMMENT_LEN {
+        return Err(format!("Comment longer than {} bytes", MAX_COMMENT_LEN));
+    }
+    if comment.contains('}') {
+        return Err("Comment may not contain '}'".into());
+    }
+    STATE.with(|s| {
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if !is_seated(g, who) {
+            return Err("You are not seated".into());
+        }
+        if ply as usize >= g.moves_san.len() {
+            return Err("No move at that ply".into());
+        }
+        if comment.is_empty() {
+            g.move_comments.remove(&ply);
+        } else {
+            g.move_comments.insert(ply, comment);
         }
+        g.updated_ns = time();
+        Ok(())
     })
 }
 
-/// Debug helper to inspect seats and token hashes (for testing)
-#[ic_cdk::query]
-fn debug_game(game_id: u64) -> Option<(Option<Principal>, Option<Principal>, [u8;32], [u8;32])> {
+/// Store a named alternative to the move at `from_ply`. The line (SAN or UCI)
+/// must replay legally from the position before that move; it is kept as SAN
+/// and appears in `export_pgn` as a parenthesized variation.
+#[update]
+fn add_variation(game_id: u64, from_ply: u32, line: Vec<String>, name: String) -> Result<(), String> {
+    if line.is_empty() {
+        return Err("Variation has no moves".into());
+    }
+    if line.len() > MAX_BATCH {
+        return Err(format!("At most {} moves per line", MAX_BATCH));
+    }
+    if name.len() > MAX_PGN_TAG_LEN {
+        return Err(format!("Name longer than {} bytes", MAX_PGN_TAG_LEN));
+    }
+    if name.contains('}') {
+        return Err("Name may not contain '}'".into());
+    }
     STATE.with(|s| {
-        s.borrow().games.get(&game_id).map(|g| {
-            (g.white, g.black, g.white_token_hash, g.black_token_hash)
-        })
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if !is_seated(g, who) {
+            return Err("You are not seated".into());
+        }
+        if from_ply as usize >= g.moves_san.len() {
+            return Err("No move at that ply".into());
+        }
+        if g.variations.len() >= MAX_VARIATIONS {
+            return Err(format!("At most {} variations per game", MAX_VARIATIONS));
+        }
+        let mut pos = replay(g, from_ply as usize)?;
+        let mut moves_san = Vec::with_capacity(line.len());
+        for (i, mv) in line.iter().enumerate() {
+            let m = parse_move_with_autopromo(&pos, mv, g.auto_queen)
+                .map_err(|e| format!("Move {} ({}): {}", i, mv, e))?;
+            moves_san.push(San::from_move(&pos, m).to_string());
+            pos = pos.play(m).map_err(|_| format!("Move {} ({}): Illegal move", i, mv))?;
+        }
+        g.variations.push(Variation { name, from_ply, moves_san });
+        g.updated_ns = time();
+        Ok(())
     })
 }
 
-// -------------------- Updates --------------------
+/// Stored variations in the order they were added; empty for unknown games.
+#[query]
+fn get_variations(game_id: u64) -> Vec<Variation> {
+    STATE.with(|s| s.borrow().games.get(&game_id).map(|g| g.variations.clone()).unwrap_or_default())
+}
 
-/// Create a new game. Returns (game_id, white_token, black_token).
+/// Tag the move at `ply` with a NAG. Every u8 is a valid NAG; `0` (null annotation) clears it.
 #[update]
-async fn create_game() -> (u64, String, String) {
-    let now = time();
-    let white_token = random_token().await;
-    let black_token = random_token().await;
-
-    let mut g = GameInternal {
-        id: 0,
-        pos: Chess::default(),
-        moves_san: vec![],
-        white: None,
-        black: None,
-        white_token_hash: hash_token(&white_token),
-        black_token_hash: hash_token(&black_token),
-        status: GameStatus::Ongoing,
-        created_ns: now,
-        updated_ns: now,
-    };
-
+fn set_move_nag(game_id: u64, ply: u32, nag: u8) -> Result<(), String> {
     STATE.with(|s| {
-        let mut s = s.borrow_mut();
-        let id = s.next_id;
-        s.next_id += 1;
-        g.id = id;
-        s.games.insert(id, g);
-        (id, white_token, black_token)
+        let who = caller();
+        let mut st = s.borrow_mut();
+        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if !is_seated(g, who) {
+            return Err("You are not seated".into());
+        }
+        if ply as usize >= g.moves_san.len() {
+            return Err("No move at that ply".into());
+        }
+        if nag == 0 {
+            g.move_nags.remove(&ply);
+        } else {
+            g.move_nags.insert(ply, nag);
+        }
+        g.updated_ns = time();
+        Ok(())
     })
 }
 
-/// Claim a seat using a one-time token (burned on success)
-#[ic_cdk::update]
-async fn join_by_token(game_id: u64, token: String) -> Result<GameView, String> {
+/// Set PGN Event/Site/Round headers. Creator or canister controller only.
+#[update]
+fn set_pgn_meta(game_id: u64, event: String, site: String, round: String) -> Result<(), String> {
+    let event = sanitize_pgn_tag(&event)?;
+    let site = sanitize_pgn_tag(&site)?;
+    let round = sanitize_pgn_tag(&round)?;
     STATE.with(|s| {
+        let who = caller();
         let mut st = s.borrow_mut();
         let g = st.games.get_mut(&game_id).ok_or("No such game")?;
-        let who = caller();
-
-        // Already seated?
-        if g.white == Some(who) || g.black == Some(who) {
-            return Err("You already occupy a seat in this game".into());
-        }
-
-        let th = hash_token(&token);
-
-        if th == g.white_token_hash {
-            if g.white.is_some() {
-                return Err("White seat already taken".into());
-            }
-            g.white = Some(who);
-            g.white_token_hash = [0u8; 32]; // burn
-            g.updated_ns = time();
-            return Ok(to_view(g));
-        } else if th == g.black_token_hash {
-            if g.black.is_some() {
-                return Err("Black seat already taken".into());
-            }
-            g.black = Some(who);
-            g.black_token_hash = [0u8; 32]; // burn
-            g.updated_ns = time();
-            return Ok(to_view(g));
+        if g.creator != who && !is_controller(&who) {
+            return Err("Only the creator can set PGN metadata".into());
         }
-
-        Err("Invalid or already-used token".into())
+        g.pgn_event = event;
+        g.pgn_site = site;
+        g.pgn_round = round;
+        g.updated_ns = time();
+        Ok(())
     })
 }
 
+/// Have `callback` receive `on_game_result(game_id: nat64, status: GameStatus)`
+/// when this game ends. Creator or canister controller only; replaces any
+/// earlier callback.
 #[update]
-fn make_move(game_id: u64, mv: String) -> Result<GameView, String> {
+fn set_result_callback(game_id: u64, callback: Principal) -> Result<(), String> {
     STATE.with(|s| {
         let who = caller();
         let mut st = s.borrow_mut();
         let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        if g.creator != who && !is_controller(&who) {
+            return Err("Only the creator can set the result callback".into());
+        }
         if !matches!(g.status, GameStatus::Ongoing) {
             return Err("Game finished".into());
         }
+        g.result_callback = Some(callback);
+        g.updated_ns = time();
+        Ok(())
+    })
+}
 
-        // Enforce turn by seat (if a seat has been claimed)
-        match g.pos.turn() {
-            Color::White => if g.white.is_some() && g.white != Some(who) { return Err("Not white".into()); }
-            Color::Black => if g.black.is_some() && g.black != Some(who) { return Err("Not black".into()); }
+/// Controller-only: start or stop the timer that deletes finished games.
+/// `ttl_ns` is how long after its last update a finished game is kept.
+#[update]
+fn set_auto_cleanup(enabled: bool, ttl_ns: u64) -> Result<(), String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can configure cleanup".into());
+    }
+    STATE.with(|s| {
+        let mut st = s.borrow_mut();
+        st.cleanup_ttl_ns = ttl_ns;
+        match (enabled, st.cleanup_timer) {
+            (true, None) => st.cleanup_timer = Some(ic_cdk_timers::set_timer_interval(CLEANUP_INTERVAL, cleanup_tick)),
+            (false, Some(id)) => {
+                ic_cdk_timers::clear_timer(id);
+                st.cleanup_timer = None;
+            }
+            _ => {}
         }
+    });
+    Ok(())
+}
 
-        let m = parse_move_with_autopromo(&g.pos, &mv)?;
-        let san_str = San::from_move(&g.pos, m).to_string();
-
-        let new_pos = g.pos.clone().play(m).map_err(|_| "Illegal move")?;
-        g.pos = new_pos;
-        g.moves_san.push(san_str);
-
-        g.status = compute_status(&g.pos);
-        g.updated_ns = time();
-        Ok(to_view(g))
+/// Controller-only identity migration: replace `old` with `new` as seat holder,
+/// creator, waiting joiner and draw offerer in every game. Returns how many games changed.
+/// Fails without changing anything if some game already seats both.
+#[update]
+fn merge_principals(old: Principal, new: Principal) -> Result<u32, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can merge principals".into());
+    }
+    if old == new {
+        return Err("Principals are the same".into());
+    }
+    STATE.with(|s| {
+        let mut st = s.borrow_mut();
+        if let Some(g) = st.games.values().find(|g| is_seated(g, old) && is_seated(g, new)) {
+            return Err(format!("Both principals are seated in game {}", g.id));
+        }
+        let mut changed = 0;
+        for g in st.games.values_mut() {
+            let mut touched = false;
+            for p in [&mut g.white, &mut g.black, &mut g.first_joiner, &mut g.draw_agree_from].into_iter().flatten() {
+                if *p == old {
+                    *p = new;
+                    touched = true;
+                }
+            }
+            if g.creator == old {
+                g.creator = new;
+                touched = true;
+            }
+            if touched {
+                changed += 1;
+            }
+        }
+        Ok(changed)
     })
 }
 
+/// Delete a game whose opponent seat was never filled, once `RECLAIM_AFTER_NS`
+/// has passed since creation. Only a seated player, or the first joiner still
+/// waiting for a random-color draw, can reclaim.
 #[update]
-fn resign(game_id: u64) -> Result<GameView, String> {
+fn reclaim_game(game_id: u64) -> Result<(), String> {
     STATE.with(|s| {
         let who = caller();
         let mut st = s.borrow_mut();
-        let g = st.games.get_mut(&game_id).ok_or("No such game")?;
+        let g = st.games.get(&game_id).ok_or("No such game")?;
+        // a waiting first joiner means the second token was never redeemed
+        if g.first_joiner != Some(who) {
+            let opponent = if g.white == Some(who) {
+                Color::Black
+            } else if g.black == Some(who) {
+                Color::White
+            } else {
+                return Err("You are not seated".into());
+            };
+            if seat_filled(g, opponent) {
+                return Err("Opponent has joined".into());
+            }
+        }
+        if time().saturating_sub(g.created_ns) < RECLAIM_AFTER_NS {
+            return Err("Still waiting for an opponent".into());
+        }
+        st.games.remove(&game_id);
+        st.keys.retain(|_, id| *id != game_id);
+        Ok(())
+    })
+}
+
+/// `export_game_blob` contents; moves are standard UCI, castling as e1g1.
+fn game_blob(g: &GameInternal) -> Result<GameBlob, String> {
+    let mut moves_uci = Vec::with_capacity(g.moves_san.len());
+    replay_with(g, g.moves_san.len(), |_, m| {
+        moves_uci.push(m.to_uci(CastlingMode::Standard).to_string());
+    })?;
+    Ok(GameBlob {
+        start_fen: g.start_fen.clone(),
+        moves_uci,
+        white: g.white,
+        black: g.black,
+        status: g.status.clone(),
+        created_ns: g.created_ns,
+        updated_ns: g.updated_ns,
+        variant: Some(g.variant),
+        auto_draw: Some(g.auto_draw),
+        options: Some(GameOptions {
+            auto_queen: Some(g.auto_queen),
+            confirm_moves: Some(g.confirm_moves),
+            strict: Some(g.strict),
+            auto_draw: Some(g.auto_draw),
+            variant: Some(g.variant),
+            move_deadline_ns: g.move_deadline_ns,
+            fair_resign: Some(g.fair_resign),
+        }),
+    })
+}
+
+/// Rebuild a game from a blob, replaying and checking every move.
+fn game_from_blob(b: GameBlob, creator: Principal, now: u64) -> Result<GameInternal, String> {
+    if b.white.is_some() && b.white == b.black {
+        return Err("Corrupt game blob: same principal in both seats".into());
+    }
+    let mut g = new_game_at(parse_fen(&b.start_fen)?, "", "", creator, now);
+    g.white_token_hash = [0u8; 32];
+    g.black_token_hash = [0u8; 32];
+    g.variant = b.variant.unwrap_or_default();
+    g.auto_draw = b.auto_draw.unwrap_or(true);
+    if let Some(opts) = &b.options {
+        apply_options(&mut g, opts);
+    }
+    // new_game_at judged the start position by standard rules
+    g.status = compute_status(&g.pos, 1, g.auto_draw, g.variant);
+    g.finished_ns = None;
+    for (i, uci) in b.moves_uci.iter().enumerate() {
         if !matches!(g.status, GameStatus::Ongoing) {
-            return Err("Game finished".into());
+            return Err(format!("Corrupt game blob: move {} after the game ended", i));
         }
-        let winner_white = if g.white == Some(who) {
-            false
-        } else if g.black == Some(who) {
-            true
-        } else {
-            return Err("You are not seated".into());
-        };
-        g.status = GameStatus::Resigned { winner_white };
-        g.updated_ns = time();
-        Ok(to_view(g))
+        parse_move_with_autopromo(&g.pos, uci, g.auto_queen)
+            .and_then(|m| play_parsed_move(&mut g, m, now))
+            .map_err(|e| format!("Corrupt game blob: move {} ({}): {}", i, uci, e))?;
+    }
+    match (&g.status, &b.status) {
+        // the moves alone decide mate, stalemate and automatic draws
+        (GameStatus::Ongoing, GameStatus::Checkmate { .. } | GameStatus::Stalemate | GameStatus::VariantWin { .. }) => {
+            return Err("Corrupt game blob: status does not match the moves".into());
+        }
+        (GameStatus::Ongoing, _) => g.status = b.status,
+        _ => {}
+    }
+    g.white = b.white;
+    g.black = b.black;
+    g.created_ns = b.created_ns;
+    g.updated_ns = b.updated_ns;
+    // the blob has no end time; its last update is the closest record
+    if !matches!(g.status, GameStatus::Ongoing) {
+        g.finished_ns = Some(b.updated_ns);
+    }
+    Ok(g)
+}
+
+/// Backup of a game's moves, seats, status and per-game options as a
+/// candid-encoded blob (see `import_game_blob`). Guests, join times,
+/// annotations, PGN headers and variations are not included.
+#[query]
+fn export_game_blob(game_id: u64) -> Result<Vec<u8>, String> {
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let g = binding.games.get(&game_id).ok_or("No such game")?;
+        candid::encode_one(game_blob(g)?).map_err(|e| e.to_string())
+    })
+}
+
+/// Controller-only: ongoing games not updated since `older_than_ns`, most stale
+/// first.
+#[query]
+fn stale_games(older_than_ns: u64) -> Result<Vec<u64>, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can list stale games".into());
+    }
+    STATE.with(|s| {
+        let st = s.borrow();
+        let mut stale: Vec<_> = st.games.values()
+            .filter(|g| matches!(g.status, GameStatus::Ongoing) && g.updated_ns < older_than_ns)
+            .collect();
+        stale.sort_by_key(|g| g.updated_ns);
+        Ok(stale.into_iter().map(|g| g.id).collect())
+    })
+}
+
+/// Controller-only: full record of a game for an arbiter, including the
+/// principals and token state that normal views leave out.
+#[query]
+fn audit(game_id: u64) -> Result<AuditRecord, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can audit games".into());
+    }
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let g = binding.games.get(&game_id).ok_or("No such game")?;
+        let mut moves_uci = Vec::with_capacity(g.moves_san.len());
+        replay_with(g, g.moves_san.len(), |_, m| {
+            moves_uci.push(m.to_uci(CastlingMode::Standard).to_string());
+        })?;
+        Ok(AuditRecord {
+            id: g.id,
+            creator: g.creator,
+            white: g.white,
+            black: g.black,
+            white_guest: g.white_guest.clone(),
+            black_guest: g.black_guest.clone(),
+            random_colors: g.random_colors,
+            first_joiner: g.first_joiner,
+            white_token_burned: g.white_token_hash == [0u8; 32],
+            black_token_burned: g.black_token_hash == [0u8; 32],
+            start_fen: g.start_fen.clone(),
+            moves_uci,
+            status: g.status.clone(),
+            created_ns: g.created_ns,
+            updated_ns: g.updated_ns,
+            result_callback: g.result_callback,
+            move_deadline_ns: g.move_deadline_ns,
+            last_move_ns: g.last_move_ns,
+            turn_started_ns: turn_started_ns(g),
+        })
     })
 }
 
+/// Controller-only: restore a blob from `export_game_blob` as a new game.
+/// Moves are replayed and must be legal; seats carry over, and since there are
+/// no tokens, empty seats stay unclaimable.
+#[update]
+fn import_game_blob(blob: Vec<u8>) -> Result<u64, String> {
+    if !is_controller(&caller()) {
+        return Err("Only a controller can import games".into());
+    }
+    let b: GameBlob = candid::decode_one(&blob).map_err(|_| "Corrupt game blob")?;
+    Ok(insert_game(game_from_blob(b, caller(), time())?))
+}
+
 #[query]
 fn export_pgn(game_id: u64) -> Result<String, String> {
     STATE.with(|s| {
         let binding = s.borrow();
         let g = binding.games.get(&game_id).ok_or("No such game")?;
         let mut pgn = String::new();
-        pgn.push_str(&format!("[Event \"IC Chess {}\"]\n", game_id));
-        pgn.push_str("[White \"?\"]\n[Black \"?\"]\n\n");
-        let mut ply = 0usize;
-        let mut move_no = 1usize;
-        for san in &g.moves_san {
-            if ply % 2 == 0 {
-                pgn.push_str(&format!("{}. {} ", move_no, san));
-                move_no += 1;
-            } else {
-                pgn.push_str(&format!("{} ", san));
-            }
-            ply += 1;
+        let event = g.pgn_event.clone().unwrap_or_else(|| format!("IC Chess {}", game_id));
+        pgn.push_str(&format!("[Event \"{}\"]\n", event));
+        pgn.push_str(&format!("[Site \"{}\"]\n", g.pgn_site.as_deref().unwrap_or("?")));
+        pgn.push_str(&format!("[Round \"{}\"]\n", g.pgn_round.as_deref().unwrap_or("?")));
+        pgn.push_str("[White \"?\"]\n[Black \"?\"]\n");
+        if g.start_fen != Fen::from_position(&Chess::default(), EnPassantMode::Legal).to_string() {
+            pgn.push_str(&format!("[SetUp \"1\"]\n[FEN \"{}\"]\n", g.start_fen));
         }
+        pgn.push('\n');
+        pgn.push_str(&pgn_movetext(g)?);
         Ok(pgn)
     })
 }
 
+/// Just the movetext `export_pgn` would emit, followed by the result token
+/// ("1-0", "0-1", "1/2-1/2" or "*"). Empty for unknown games.
+#[query]
+fn movetext(game_id: u64) -> String {
+    STATE.with(|s| {
+        let binding = s.borrow();
+        let Some(g) = binding.games.get(&game_id) else { return String::new() };
+        let Ok(mut text) = pgn_movetext(g) else { return String::new() };
+        text.push_str(match outcome(&g.status) {
+            Some(Outcome::WhiteWins) => "1-0",
+            Some(Outcome::BlackWins) => "0-1",
+            Some(Outcome::Draw) => "1/2-1/2",
+            None => "*",
+        });
+        text
+    })
+}
+
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(fen: &str) -> Chess {
+        parse_fen(fen).unwrap()
+    }
+
+    #[test]
+    fn back_rank_mate_by_white() {
+        let status = compute_status(&pos("R5k1/5ppp/8/8/8/8/8/6K1 b - - 1 1"), 1, true, Variant::Standard);
+        assert!(matches!(status, GameStatus::Checkmate { winner_white: true }));
+    }
+
+    #[test]
+    fn back_rank_mate_by_black() {
+        let status = compute_status(&pos("6k1/8/8/8/8/8/5PPP/r5K1 w - - 1 2"), 1, true, Variant::Standard);
+        assert!(matches!(status, GameStatus::Checkmate { winner_white: false }));
+    }
+
+    #[test]
+    fn queen_stalemate() {
+        let status = compute_status(&pos("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), 1, true, Variant::Standard);
+        assert!(matches!(status, GameStatus::Stalemate));
+    }
+
+    #[test]
+    fn same_color_bishops_are_insufficient() {
+        let status = compute_status(&pos("4k3/8/8/8/8/8/8/2B1K1b1 w - - 0 1"), 1, true, Variant::Standard);
+        assert!(matches!(status, GameStatus::Draw { reason } if reason == "insufficient material"));
+    }
+
+    #[test]
+    fn opposite_color_bishops_play_on() {
+        let status = compute_status(&pos("4k3/8/8/8/8/8/8/2B1K2b w - - 0 1"), 1, true, Variant::Standard);
+        assert!(matches!(status, GameStatus::Ongoing));
+    }
+
+    fn move_error(fen: &str, mv: &str) -> String {
+        parse_move_with_autopromo(&pos(fen), mv, true).unwrap_err()
+    }
+
+    const CHECK_ERR: &str = "Illegal move: it would leave your king in check";
+
+    #[test]
+    fn king_cannot_step_into_check() {
+        assert_eq!(move_error("3rk3/8/8/8/8/8/8/4K3 w - - 0 1", "e1d1"), CHECK_ERR);
+        assert_eq!(move_error("3rk3/8/8/8/8/8/8/4K3 w - - 0 1", "Kd1"), CHECK_ERR);
+    }
+
+    #[test]
+    fn move_must_answer_check() {
+        assert_eq!(move_error("4k3/8/8/8/8/8/P7/r3K3 w - - 0 1", "a2a3"), CHECK_ERR);
+        assert_eq!(move_error("4k3/8/8/8/8/8/P7/r3K3 w - - 0 1", "a3"), CHECK_ERR);
+    }
+
+    #[test]
+    fn nonsense_moves_are_plain_errors() {
+        let start = Fen::from_position(&Chess::default(), EnPassantMode::Legal).to_string();
+        assert_eq!(move_error(&start, "e2e5"), "Illegal move");
+        assert_eq!(move_error(&start, "Nf6"), "Illegal move");
+        assert_eq!(move_error(&start, "e3e4"), "Illegal move");
+        assert_eq!(move_error(&start, "hello"), "Move must be SAN (e.g. 'e4') or UCI ('e2e4'/'e7e8q')");
+    }
+
+    #[test]
+    fn en_passant_exposing_king_on_rank() {
+        assert_eq!(move_error("8/8/8/K2pP2r/8/8/8/4k3 w - d6 0 2", "e5d6"), CHECK_ERR);
+        assert_eq!(move_error("8/8/8/K2pP2r/8/8/8/4k3 w - d6 0 2", "exd6"), CHECK_ERR);
+    }
+
+    #[test]
+    fn pinned_knight_cannot_move() {
+        let fen = "4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1";
+        assert_eq!(move_error(fen, "e2c3"), "That piece is pinned and cannot move");
+        assert_eq!(move_error(fen, "Nc3"), "That piece is pinned and cannot move");
+    }
+
+    #[test]
+    fn pinned_bishop_moves_only_along_the_pin() {
+        let fen = "4k3/8/8/b7/8/8/3B4/4K3 w - - 0 1";
+        assert_eq!(move_error(fen, "d2e3"), "That piece is pinned and can only move along the pin");
+        assert_eq!(move_error(fen, "Be3"), "That piece is pinned and can only move along the pin");
+        assert_eq!(parse_ok(fen, "Bxa5").to(), Square::A5);
+    }
+
+    #[test]
+    fn null_moves_are_rejected() {
+        let start = Chess::default();
+        for mv in ["0000", "--"] {
+            assert_eq!(parse_move_with_autopromo(&start, mv, true).unwrap_err(), "Null moves are not allowed");
+        }
+    }
+
+    fn parse_ok(fen: &str, mv: &str) -> ShMove {
+        parse_move_with_autopromo(&pos(fen), mv, true).unwrap()
+    }
+
+    #[test]
+    fn long_algebraic_splits_role_and_squares() {
+        assert_eq!(long_algebraic_to_uci("Ng1-f3"), Some((Some(Role::Knight), "g1f3".to_string())));
+        assert_eq!(long_algebraic_to_uci("e4xd5+"), Some((None, "e4d5".to_string())));
+        assert_eq!(long_algebraic_to_uci("e7-e8=Q"), Some((None, "e7e8Q".to_string())));
+        assert_eq!(long_algebraic_to_uci("e4"), None);
+        assert_eq!(long_algebraic_to_uci("Nf3"), None);
+    }
+
+    #[test]
+    fn long_algebraic_moves_parse() {
+        let start = Fen::from_position(&Chess::default(), EnPassantMode::Legal).to_string();
+        let m = parse_ok(&start, "Ng1-f3");
+        assert_eq!((m.role(), m.from(), m.to()), (Role::Knight, Some(Square::G1), Square::F3));
+        let m = parse_ok(&start, "e2-e4");
+        assert_eq!((m.from(), m.to()), (Some(Square::E2), Square::E4));
+        let m = parse_ok("4k3/8/8/8/7q/8/8/K3R3 b - - 0 1", "Qh4xe1");
+        assert_eq!((m.role(), m.to(), m.capture()), (Role::Queen, Square::E1, Some(Role::Rook)));
+        assert_eq!(parse_ok("k7/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7-e8=Q").promotion(), Some(Role::Queen));
+        assert_eq!(parse_ok("k7/4P3/8/8/8/8/8/4K3 w - - 0 1", "e7-e8=N").promotion(), Some(Role::Knight));
+    }
+
+    #[test]
+    fn long_algebraic_rejects_wrong_piece_letter() {
+        let start = Fen::from_position(&Chess::default(), EnPassantMode::Legal).to_string();
+        assert_eq!(move_error(&start, "Bg1-f3"), "Illegal move");
+    }
+
+    #[test]
+    fn standard_san_still_parses() {
+        let start = Fen::from_position(&Chess::default(), EnPassantMode::Legal).to_string();
+        assert_eq!(parse_ok(&start, "Nf3").to(), Square::F3);
+        assert_eq!(parse_ok(&start, "e4").to(), Square::E4);
+        assert!(parse_ok("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "O-O").is_castle());
+        assert!(parse_ok("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "O-O-O").is_castle());
+        let m = parse_ok("rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2", "exd5");
+        assert_eq!((m.from(), m.to(), m.capture()), (Some(Square::E4), Square::D5, Some(Role::Pawn)));
+    }
+
+    #[test]
+    fn fifty_move_rule_waits_for_a_claim_without_auto_draw() {
+        let fen = "4k3/8/8/8/8/8/8/R3K3 w - - 100 80";
+        assert!(matches!(compute_status(&pos(fen), 1, true, Variant::Standard), GameStatus::Draw { reason } if reason == "fifty-move rule"));
+        assert!(matches!(compute_status(&pos(fen), 1, false, Variant::Standard), GameStatus::Ongoing));
+        assert!(fifty_move_holds(&pos(fen)));
+    }
+
+    #[test]
+    fn seventy_five_move_rule_always_ends_the_game() {
+        let fen = "4k3/8/8/8/8/8/8/R3K3 w - - 150 100";
+        assert!(matches!(compute_status(&pos(fen), 1, false, Variant::Standard), GameStatus::Draw { reason } if reason == "seventy-five-move rule"));
+    }
+
+    #[test]
+    fn san_check_and_mate_suffixes_parse() {
+        let before = pos("k7/4P3/8/8/8/8/8/4K3 w - - 0 1");
+        let m = parse_move_with_autopromo(&before, "e8=Q+", true).unwrap();
+        assert_eq!((m.to(), m.promotion()), (Square::E8, Some(Role::Queen)));
+        assert!(before.play(m).unwrap().is_check());
+
+        let before = pos("8/8/8/8/8/kPPP4/PpKP4/RBNQ4 b - - 0 1");
+        let m = parse_move_with_autopromo(&before, "bxa1=N#", true).unwrap();
+        assert_eq!((m.to(), m.capture(), m.promotion()), (Square::A1, Some(Role::Rook), Some(Role::Knight)));
+        let after = before.play(m).unwrap();
+        assert!(matches!(compute_status(&after, 1, true, Variant::Standard), GameStatus::Checkmate { winner_white: false }));
+    }
+
+    fn game(fen: &str) -> GameInternal {
+        new_game_at(pos(fen), "", "", Principal::anonymous(), 0)
+    }
+
+    fn play(g: &mut GameInternal, mv: &str) {
+        let m = parse_move_with_autopromo(&g.pos, mv, true).unwrap();
+        play_parsed_move(g, m, 0).unwrap();
+    }
+
+    #[test]
+    fn third_check_wins_three_check() {
+        let mut g = game("4k3/8/8/8/8/8/8/R3K3 w - - 0 1");
+        g.variant = Variant::ThreeCheck;
+        for mv in ["Ra8+", "Ke7", "Ra7+", "Kd6"] {
+            play(&mut g, mv);
+        }
+        assert!(matches!(g.status, GameStatus::Ongoing));
+        assert_eq!(g.white_checks_given, 2);
+        play(&mut g, "Ra6+");
+        assert!(matches!(&g.status, GameStatus::VariantWin { winner_white: true, reason } if reason == "three checks"));
+    }
+
+    #[test]
+    fn king_reaching_the_center_wins_king_of_the_hill() {
+        let fen = "4k3/8/8/8/8/3K4/8/7R w - - 0 1";
+        let mut g = game(fen);
+        play(&mut g, "Kd4");
+        assert!(matches!(g.status, GameStatus::Ongoing));
+
+        let mut g = game(fen);
+        g.variant = Variant::KingOfTheHill;
+        play(&mut g, "Kd4");
+        assert!(matches!(&g.status, GameStatus::VariantWin { winner_white: true, reason } if reason == "king of the hill"));
+    }
+
+    #[test]
+    fn burned_seat_never_matches() {
+        assert!(token_matches(hash_token("abc"), hash_token("abc")));
+        assert!(!token_matches(hash_token("abc"), hash_token("abd")));
+        assert!(!token_matches([0u8; 32], [0u8; 32]));
+        assert!(!token_matches(hash_token("abc"), [0u8; 32]));
+    }
+
+    #[test]
+    fn fair_resign_against_lone_king_is_a_draw() {
+        let mut g = game("4k3/8/8/8/8/8/8/3QK3 w - - 0 1");
+        assert!(matches!(resign_status(&g, false), GameStatus::Resigned { winner_white: false }));
+        g.fair_resign = true;
+        assert!(matches!(resign_status(&g, false), GameStatus::Draw { .. }));
+        assert!(matches!(resign_status(&g, true), GameStatus::Resigned { winner_white: true }));
+    }
+
+    #[test]
+    fn custom_start_fen_keeps_move_counters() {
+        let mut g = game("4k3/8/8/8/8/8/4P3/4K2R w K - 7 20");
+        let fen = |g: &GameInternal| Fen::from_position(&g.pos, EnPassantMode::Legal).to_string();
+        let expected = [
+            ("Kf1", "4k3/8/8/8/8/8/4P3/5K1R b - - 8 20"),
+            ("Kd7", "8/3k4/8/8/8/8/4P3/5K1R w - - 9 21"),
+            ("e4", "8/3k4/8/8/4P3/8/8/5K1R b - - 0 21"),
+            ("Kd6", "8/8/3k4/8/4P3/8/8/5K1R w - - 1 22"),
+        ];
+        assert_eq!(fen(&g), "4k3/8/8/8/8/8/4P3/4K2R w K - 7 20");
+        for (mv, after) in expected {
+            play(&mut g, mv);
+            assert_eq!(fen(&g), after);
+        }
+        let replayed = replay(&g, g.moves_san.len()).unwrap();
+        assert_eq!(Fen::from_position(&replayed, EnPassantMode::Legal).to_string(), fen(&g));
+    }
+
+    #[test]
+    fn uci_castling_uses_the_king_destination() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+        assert!(parse_ok(fen, "e1g1").is_castle());
+        assert!(parse_ok(fen, "e1c1").is_castle());
+        assert!(parse_ok(fen, "e1h1").is_castle());
+    }
+
+    #[test]
+    fn game_blob_round_trip_with_castling() {
+        let mut g = game("r3k2r/pppppppp/8/8/8/8/PPPPPPPP/R3K2R w KQkq - 0 1");
+        g.strict = true;
+        g.fair_resign = true;
+        g.move_deadline_ns = Some(60);
+        for mv in ["O-O", "O-O-O", "a3", "h6"] {
+            play(&mut g, mv);
+        }
+        let blob = candid::encode_one(game_blob(&g).unwrap()).unwrap();
+        let b: GameBlob = candid::decode_one(&blob).unwrap();
+        assert_eq!(b.moves_uci, ["e1g1", "e8c8", "a2a3", "h7h6"]);
+        let restored = game_from_blob(b, Principal::anonymous(), 0).unwrap();
+        assert_eq!(restored.moves_san, g.moves_san);
+        assert!(restored.strict && restored.fair_resign);
+        assert_eq!(restored.move_deadline_ns, Some(60));
+        assert_eq!(
+            Fen::from_position(&restored.pos, EnPassantMode::Legal).to_string(),
+            Fen::from_position(&g.pos, EnPassantMode::Legal).to_string(),
+        );
+    }
+
+    #[test]
+    fn lone_minor_piece_can_still_win_three_check() {
+        let fen = "4k3/8/8/8/8/8/3r4/2B1K3 w - - 0 1";
+        let mut g = game(fen);
+        play(&mut g, "Kxd2");
+        assert!(matches!(&g.status, GameStatus::Draw { reason } if reason == "insufficient material"));
+
+        let mut g = game(fen);
+        g.variant = Variant::ThreeCheck;
+        play(&mut g, "Kxd2");
+        assert!(matches!(g.status, GameStatus::Ongoing));
+        assert!(!cannot_win(&g.pos, Color::White, g.variant));
+        assert!(cannot_win(&g.pos, Color::Black, g.variant));
+    }
+
+    #[test]
+    fn bare_kings_play_on_in_king_of_the_hill() {
+        let mut g = game("4k3/8/8/8/8/8/3r4/4K3 w - - 0 1");
+        g.variant = Variant::KingOfTheHill;
+        play(&mut g, "Kxd2");
+        assert!(matches!(g.status, GameStatus::Ongoing));
+        assert!(!cannot_win(&g.pos, Color::White, g.variant));
+        for mv in ["Ke7", "Ke3", "Kf6", "Ke4"] {
+            play(&mut g, mv);
+        }
+        assert!(matches!(&g.status, GameStatus::VariantWin { winner_white: true, reason } if reason == "king of the hill"));
+    }
+
+    #[test]
+    fn puzzle_solutions_may_castle() {
+        let start = pos("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        let line = ["e1g1", "e8c8"].map(String::from);
+        assert_eq!(puzzle_solution(&start, &line).unwrap(), line);
+        let king_takes_rook = ["e1h1".to_string()];
+        assert_eq!(puzzle_solution(&start, &king_takes_rook).unwrap(), ["e1g1"]);
+    }
+
+    #[test]
+    fn first_move_clock_starts_when_both_seats_are_filled() {
+        let mut g = game("4k3/8/8/8/8/8/8/R3K3 w - - 0 1");
+        g.move_deadline_ns = Some(1_000);
+        g.white_joined_ns = Some(100);
+        g.black_joined_ns = Some(500);
+        assert_eq!(turn_started_ns(&g), 500);
+        assert_eq!(move_time_left_ns(&g, 1_200), Some(300));
+        assert!(!deadline_passed(&g, 1_000, 1_500));
+        assert!(deadline_passed(&g, 1_000, 1_501));
+
+        let m = parse_move_with_autopromo(&g.pos, "Ra7", true).unwrap();
+        play_parsed_move(&mut g, m, 2_000).unwrap();
+        assert_eq!(turn_started_ns(&g), 2_000);
+        assert_eq!(move_time_left_ns(&g, 2_400), Some(600));
+        assert!(!deadline_passed(&g, 1_000, 3_000));
+    }
+}
@@ -123,7 +123,7 @@ export default function Board({ gameId, shareTokens }) {
       const g = gameRef.current;
       if (!a || !g) return;
 
-      const res = await a.make_move(g.id, from + to);
+      const res = await a.make_move(g.id, from + to, []);
       if (res && res.Ok) {
         const newG = res.Ok;
         setGame(newG);